@@ -0,0 +1,537 @@
+use num_bigint::BigUint;
+
+use crate::{matrix::Triangle, matrix_builder::NussinovMatrixBuilder};
+
+// Algebraic dynamic programming: a tree grammar describes the search space of structures and an
+// algebra evaluates and chooses among its candidates. Grammars are written as rules such as
+// `S -> aSb | aS | Sa | SS | ε`, where uppercase letters are nonterminals, `a` is a nucleotide and
+// `b` is a nucleotide paired with the closest unmatched `a` to its left. Rules are separated by
+// newlines or `;` and the first rule defines the axiom.
+pub struct Grammar {
+    names: Vec<char>,
+    productions: Vec<Vec<Production>>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct Production(Vec<Symbol>);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Symbol {
+    Unpaired,
+    Open,
+    Close,
+    Nonterminal(usize),
+}
+
+// Evaluates candidate structures, which are built from the empty structure by appending unpaired
+// nucleotides, enclosing them in pairs and concatenating them. The choice function picks the
+// candidates to keep for every subsequence, e.g. the best one or all of them.
+pub trait Algebra {
+    type Value: Clone + PartialEq;
+
+    fn empty(&self) -> Self::Value;
+
+    fn unpaired(&self, i: usize) -> Self::Value;
+
+    // The pair of the nucleotides `i` and `j` around `inner`, scored `score`.
+    fn pair(&self, i: usize, j: usize, score: usize, inner: &Self::Value) -> Self::Value;
+
+    fn concat(&self, left: &Self::Value, right: &Self::Value) -> Self::Value;
+
+    fn choice(&self, candidates: Vec<Self::Value>) -> Vec<Self::Value>;
+
+    // Whether `choice` only picks among its candidates instead of combining them.
+    fn is_selective(&self) -> bool {
+        true
+    }
+
+    fn show(&self, value: &Self::Value) -> String;
+}
+
+// Best score.
+pub struct Score;
+
+// Number of candidates, i.e. of derivations if the grammar is ambiguous.
+pub struct Count;
+
+// Every candidate in dot-bracket notation.
+pub struct DotBracket;
+
+// Abstract shapes of level 5: unpaired nucleotides are dropped and every helix, including its
+// bulges and interior loops, becomes a single `[]`. All distinct shapes are kept.
+pub struct Shape;
+
+// Lexicographic product: chooses with the first algebra and, among the candidates sharing each
+// chosen first value, with the second. `Product(Score, Count)` thus counts the optimal candidates
+// and `Product(Shape, Score)` finds the best score of every shape. The first algebra has to be
+// selective; `Count` sums its candidates, so no candidate would share its chosen value.
+pub struct Product<A, B>(pub A, pub B);
+
+impl Grammar {
+    pub fn parse(rules: &str) -> Result<Grammar, String> {
+        let rules: Vec<(char, &str)> = rules
+            .split(['\n', ';'])
+            .map(str::trim)
+            .filter(|rule| !rule.is_empty())
+            .map(|rule| {
+                let (lhs, rhs) = rule
+                    .split_once("->")
+                    .ok_or_else(|| format!("The rule {} has no `->`", rule))?;
+                match lhs.trim().chars().collect::<Vec<char>>()[..] {
+                    [name] if name.is_ascii_uppercase() => Ok((name, rhs)),
+                    _ => Err(format!("{} is not a nonterminal", lhs.trim())),
+                }
+            })
+            .collect::<Result<_, String>>()?;
+        if rules.is_empty() {
+            return Err("The grammar has no rules".into());
+        }
+
+        let mut names: Vec<char> = vec![];
+        for (name, _) in &rules {
+            if !names.contains(name) {
+                names.push(*name);
+            }
+        }
+        let mut productions = vec![vec![]; names.len()];
+        for (name, rhs) in rules {
+            let index = names.iter().position(|n| *n == name).unwrap();
+            for alternative in rhs.split('|') {
+                productions[index].push(Grammar::parse_production(alternative, &names)?);
+            }
+        }
+
+        Ok(Grammar { names, productions })
+    }
+
+    fn parse_production(alternative: &str, names: &[char]) -> Result<Production, String> {
+        let mut symbols = vec![];
+        let mut unmatched = vec![];
+        for c in alternative
+            .chars()
+            .filter(|c| !c.is_whitespace() && *c != 'ε')
+        {
+            let symbol = match c {
+                'a' => {
+                    unmatched.push(symbols.len());
+                    Symbol::Unpaired
+                }
+                'b' => match unmatched.pop() {
+                    Some(opening) => {
+                        symbols[opening] = Symbol::Open;
+                        Symbol::Close
+                    }
+                    None => return Err(format!("`b` in {} closes no `a`", alternative.trim())),
+                },
+                _ => match names.iter().position(|n| *n == c) {
+                    Some(index) => Symbol::Nonterminal(index),
+                    None => return Err(format!("{} is neither a terminal nor defined", c)),
+                },
+            };
+            symbols.push(symbol);
+        }
+
+        let nonterminals = symbols
+            .iter()
+            .filter(|s| matches!(s, Symbol::Nonterminal(_)))
+            .count();
+        if nonterminals > 2 {
+            return Err(format!(
+                "{} has more than two nonterminals",
+                alternative.trim()
+            ));
+        }
+        if nonterminals == 1 && symbols.len() == 1 {
+            return Err(format!(
+                "The unit production {} is not supported",
+                alternative.trim()
+            ));
+        }
+        Ok(Production(symbols))
+    }
+
+    // Every chosen candidate of the axiom for the whole sequence. Pairs and unpaired nucleotides
+    // are restricted by the scoring and constraints of `matrix_builder`.
+    pub fn evaluate<A: Algebra>(
+        &self,
+        matrix_builder: &NussinovMatrixBuilder,
+        len: usize,
+        algebra: &A,
+    ) -> Vec<A::Value> {
+        let mut tables = vec![Triangle::filled_with(vec![], len + 1); self.names.len()];
+
+        for d in 0..=len {
+            for i in 0..=(len - d) {
+                for (index, productions) in self.productions.iter().enumerate() {
+                    let candidates = productions
+                        .iter()
+                        .flat_map(|p| self.derive(p, i, i + d, &tables, matrix_builder, algebra))
+                        .collect();
+                    tables[index][(i, i + d)] = algebra.choice(candidates);
+                }
+            }
+        }
+
+        tables[0][(0, len)].clone()
+    }
+
+    // Candidates of a production for the subsequence [i, j). A nonterminal may derive the empty
+    // subsequence unless the production has no terminals, which could otherwise loop forever.
+    fn derive<A: Algebra>(
+        &self,
+        production: &Production,
+        i: usize,
+        j: usize,
+        tables: &[Triangle<Vec<A::Value>>],
+        matrix_builder: &NussinovMatrixBuilder,
+        algebra: &A,
+    ) -> Vec<A::Value> {
+        let symbols = &production.0;
+        let terminals = symbols
+            .iter()
+            .filter(|s| !matches!(s, Symbol::Nonterminal(_)))
+            .count();
+        let nonterminals = symbols.len() - terminals;
+        let free = match (j - i).checked_sub(terminals) {
+            Some(free) => free,
+            None => return vec![],
+        };
+        let min_span = usize::from(terminals == 0);
+
+        let splits: Vec<[usize; 2]> = match nonterminals {
+            0 if free == 0 => vec![[0, 0]],
+            0 => vec![],
+            1 => vec![[free, 0]],
+            _ => (min_span..=free.saturating_sub(min_span))
+                .map(|s| [s, free - s])
+                .collect(),
+        };
+
+        splits
+            .into_iter()
+            .flat_map(|spans| self.derive_split(symbols, i, spans, tables, matrix_builder, algebra))
+            .collect()
+    }
+
+    fn derive_split<A: Algebra>(
+        &self,
+        symbols: &[Symbol],
+        i: usize,
+        spans: [usize; 2],
+        tables: &[Triangle<Vec<A::Value>>],
+        matrix_builder: &NussinovMatrixBuilder,
+        algebra: &A,
+    ) -> Vec<A::Value> {
+        // Candidates of every open pair, outermost first, with the position of its `a`.
+        let mut frames: Vec<(usize, Vec<A::Value>)> = vec![(i, vec![algebra.empty()])];
+        let mut position = i;
+        let mut spans = spans.into_iter();
+
+        for symbol in symbols {
+            match symbol {
+                Symbol::Unpaired => {
+                    if !matrix_builder.can_be_unpaired(position) {
+                        return vec![];
+                    }
+                    let unpaired = [algebra.unpaired(position)];
+                    append(algebra, &mut frames.last_mut().unwrap().1, &unpaired);
+                    position += 1;
+                }
+                Symbol::Open => {
+                    frames.push((position, vec![algebra.empty()]));
+                    position += 1;
+                }
+                Symbol::Close => {
+                    let (open, inner) = frames.pop().unwrap();
+                    let score = match matrix_builder.pair_score(open, position) {
+                        Some(score) => score,
+                        None => return vec![],
+                    };
+                    let pairs: Vec<A::Value> = inner
+                        .iter()
+                        .map(|inner| algebra.pair(open, position, score, inner))
+                        .collect();
+                    append(algebra, &mut frames.last_mut().unwrap().1, &pairs);
+                    position += 1;
+                }
+                Symbol::Nonterminal(index) => {
+                    let span = spans.next().unwrap();
+                    let values = &tables[*index][(position, position + span)];
+                    append(algebra, &mut frames.last_mut().unwrap().1, values);
+                    position += span;
+                }
+            }
+        }
+
+        frames.pop().unwrap().1
+    }
+}
+
+// Concatenates every candidate with every value.
+fn append<A: Algebra>(algebra: &A, candidates: &mut Vec<A::Value>, values: &[A::Value]) {
+    *candidates = candidates
+        .iter()
+        .flat_map(|candidate| values.iter().map(|v| algebra.concat(candidate, v)))
+        .collect();
+}
+
+impl Algebra for Score {
+    type Value = usize;
+
+    fn empty(&self) -> Self::Value {
+        0
+    }
+
+    fn unpaired(&self, _i: usize) -> Self::Value {
+        0
+    }
+
+    fn pair(&self, _i: usize, _j: usize, score: usize, inner: &Self::Value) -> Self::Value {
+        score + inner
+    }
+
+    fn concat(&self, left: &Self::Value, right: &Self::Value) -> Self::Value {
+        left + right
+    }
+
+    fn choice(&self, candidates: Vec<Self::Value>) -> Vec<Self::Value> {
+        candidates.into_iter().max().into_iter().collect()
+    }
+
+    fn show(&self, value: &Self::Value) -> String {
+        value.to_string()
+    }
+}
+
+impl Algebra for Count {
+    type Value = BigUint;
+
+    fn empty(&self) -> Self::Value {
+        BigUint::from(1u8)
+    }
+
+    fn unpaired(&self, _i: usize) -> Self::Value {
+        BigUint::from(1u8)
+    }
+
+    fn pair(&self, _i: usize, _j: usize, _score: usize, inner: &Self::Value) -> Self::Value {
+        inner.clone()
+    }
+
+    fn concat(&self, left: &Self::Value, right: &Self::Value) -> Self::Value {
+        left * right
+    }
+
+    fn choice(&self, candidates: Vec<Self::Value>) -> Vec<Self::Value> {
+        if candidates.is_empty() {
+            return vec![];
+        }
+        vec![candidates.into_iter().sum()]
+    }
+
+    fn is_selective(&self) -> bool {
+        false
+    }
+
+    fn show(&self, value: &Self::Value) -> String {
+        value.to_string()
+    }
+}
+
+impl Algebra for DotBracket {
+    type Value = String;
+
+    fn empty(&self) -> Self::Value {
+        String::new()
+    }
+
+    fn unpaired(&self, _i: usize) -> Self::Value {
+        ".".into()
+    }
+
+    fn pair(&self, _i: usize, _j: usize, _score: usize, inner: &Self::Value) -> Self::Value {
+        format!("({})", inner)
+    }
+
+    fn concat(&self, left: &Self::Value, right: &Self::Value) -> Self::Value {
+        format!("{}{}", left, right)
+    }
+
+    fn choice(&self, candidates: Vec<Self::Value>) -> Vec<Self::Value> {
+        candidates
+    }
+
+    fn show(&self, value: &Self::Value) -> String {
+        value.clone()
+    }
+}
+
+impl Algebra for Shape {
+    type Value = String;
+
+    fn empty(&self) -> Self::Value {
+        String::new()
+    }
+
+    fn unpaired(&self, _i: usize) -> Self::Value {
+        String::new()
+    }
+
+    fn pair(&self, _i: usize, _j: usize, _score: usize, inner: &Self::Value) -> Self::Value {
+        // A single helix inside continues this one.
+        let mut depth = 0;
+        let closes_at_end = inner.char_indices().all(|(index, c)| {
+            depth += if c == '[' { 1 } else { -1 };
+            depth > 0 || index == inner.len() - 1
+        });
+        if !inner.is_empty() && closes_at_end {
+            return inner.clone();
+        }
+        format!("[{}]", inner)
+    }
+
+    fn concat(&self, left: &Self::Value, right: &Self::Value) -> Self::Value {
+        format!("{}{}", left, right)
+    }
+
+    fn choice(&self, candidates: Vec<Self::Value>) -> Vec<Self::Value> {
+        let mut shapes = vec![];
+        for candidate in candidates {
+            if !shapes.contains(&candidate) {
+                shapes.push(candidate);
+            }
+        }
+        shapes
+    }
+
+    fn show(&self, value: &Self::Value) -> String {
+        match value.is_empty() {
+            true => "_".into(),
+            false => value.clone(),
+        }
+    }
+}
+
+impl<A: Algebra, B: Algebra> Algebra for Product<A, B> {
+    type Value = (A::Value, B::Value);
+
+    fn empty(&self) -> Self::Value {
+        (self.0.empty(), self.1.empty())
+    }
+
+    fn unpaired(&self, i: usize) -> Self::Value {
+        (self.0.unpaired(i), self.1.unpaired(i))
+    }
+
+    fn pair(&self, i: usize, j: usize, score: usize, inner: &Self::Value) -> Self::Value {
+        (
+            self.0.pair(i, j, score, &inner.0),
+            self.1.pair(i, j, score, &inner.1),
+        )
+    }
+
+    fn concat(&self, left: &Self::Value, right: &Self::Value) -> Self::Value {
+        (
+            self.0.concat(&left.0, &right.0),
+            self.1.concat(&left.1, &right.1),
+        )
+    }
+
+    fn choice(&self, candidates: Vec<Self::Value>) -> Vec<Self::Value> {
+        let firsts = self
+            .0
+            .choice(candidates.iter().map(|(a, _)| a.clone()).collect());
+        let mut chosen: Vec<Self::Value> = vec![];
+
+        for (index, first) in firsts.iter().enumerate() {
+            if firsts[..index].contains(first) {
+                continue;
+            }
+            let group = candidates
+                .iter()
+                .filter(|(a, _)| a == first)
+                .map(|(_, b)| b.clone())
+                .collect();
+            chosen.extend(
+                self.1
+                    .choice(group)
+                    .into_iter()
+                    .map(|second| (first.clone(), second)),
+            );
+        }
+
+        chosen
+    }
+
+    fn is_selective(&self) -> bool {
+        self.0.is_selective() && self.1.is_selective()
+    }
+
+    fn show(&self, value: &Self::Value) -> String {
+        format!("{} {}", self.0.show(&value.0), self.1.show(&value.1))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use num_bigint::BigUint;
+
+    use crate::{
+        brute_force,
+        matrix_builder::NussinovMatrixBuilder,
+        nussinov::{Nussinov, RNASequence},
+    };
+
+    use super::{Count, DotBracket, Grammar};
+
+    // Every structure has exactly one derivation: the last nucleotide is unpaired or closes a pair.
+    const NUSSINOV: &str = "S -> ε | Sa | SaSb";
+
+    #[test]
+    fn counting_matches_the_structure_count() {
+        let grammar = Grammar::parse(NUSSINOV).unwrap();
+        for sequence in brute_force::SEQUENCES {
+            let (_, total) = Nussinov::new(sequence, 1).count().unwrap();
+            let sequence = RNASequence::new(sequence).unwrap();
+            let n = sequence.len();
+            let matrix_builder = NussinovMatrixBuilder::new(&sequence, 1);
+            assert_eq!(grammar.evaluate(&matrix_builder, n, &Count), vec![total]);
+
+            for matrix_builder in brute_force::builders(&sequence) {
+                let total = BigUint::from(brute_force::structures(&matrix_builder, n).len());
+                assert_eq!(grammar.evaluate(&matrix_builder, n, &Count), vec![total]);
+            }
+        }
+    }
+
+    #[test]
+    fn ambiguous_grammars_count_derivations() {
+        // The only structure of AAAA is derived by taking each nucleotide off either end.
+        let grammar = Grammar::parse("S -> ε | aS | Sa").unwrap();
+        let sequence = RNASequence::new("AAAA").unwrap();
+        let matrix_builder = NussinovMatrixBuilder::new(&sequence, 1);
+
+        let count = grammar.evaluate(&matrix_builder, 4, &Count);
+        assert_eq!(count, vec![BigUint::from(16u8)]);
+        let structures = grammar.evaluate(&matrix_builder, 4, &DotBracket);
+        assert_eq!(structures, vec!["....".to_string(); 16]);
+    }
+
+    #[test]
+    fn rejects_malformed_rules() {
+        let malformed = [
+            "",
+            "S aS",
+            "s -> aS",
+            "SS -> a",
+            "S -> aSb | b",
+            "S -> aT",
+            "S -> x",
+            "S -> STU; T -> a; U -> a",
+            "S -> T; T -> a",
+        ];
+        for rules in malformed {
+            assert!(Grammar::parse(rules).is_err(), "{} was accepted", rules);
+        }
+    }
+}
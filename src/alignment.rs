@@ -0,0 +1,281 @@
+use crate::{
+    constraint::Constraint,
+    matrix::ScoreOverflow,
+    matrix_builder::NussinovMatrixBuilder,
+    nussinov::RNASequence,
+    pair_scoring::PairScoring,
+    path_converter::{NussinovPathConverter, PathConverter},
+    traceback_paths::OptimalStructures,
+};
+
+// A multiple sequence alignment of RNA sequences; every row has the same number of columns.
+pub struct Alignment {
+    names: Vec<String>,
+    rows: Vec<RNASequence>,
+}
+
+// Scores the columns of an alignment jointly, as RNAalifold does, so that `NussinovMatrixBuilder`
+// folds the alignment like a single sequence. Scores are given in hundredths, see `score_at`.
+pub struct AlignmentScoring<'a> {
+    alignment: &'a Alignment,
+    scoring: &'a dyn PairScoring,
+}
+
+impl Alignment {
+    // Reads a Stockholm or Clustal alignment, depending on its header line.
+    pub fn from_file(path: &str) -> Result<Alignment, String> {
+        let content = std::fs::read_to_string(path).map_err(|e| e.to_string())?;
+        let header = content.lines().next().unwrap_or_default();
+
+        if header.starts_with("# STOCKHOLM") {
+            Alignment::from_stockholm(&content)
+        } else if header.starts_with("CLUSTAL") {
+            Alignment::from_clustal(&content)
+        } else {
+            Err("The alignment is neither in Stockholm nor in Clustal format".into())
+        }
+    }
+
+    // Annotation lines (`#`) are skipped; sequences may be split over several blocks.
+    pub fn from_stockholm(content: &str) -> Result<Alignment, String> {
+        let lines = content
+            .lines()
+            .map(str::trim)
+            .take_while(|line| *line != "//")
+            .filter(|line| !line.is_empty() && !line.starts_with('#'));
+
+        Alignment::from_blocks(lines)
+    }
+
+    // Conservation lines, which start with whitespace, and trailing residue counts are skipped.
+    pub fn from_clustal(content: &str) -> Result<Alignment, String> {
+        let lines = content
+            .lines()
+            .skip(1)
+            .filter(|line| !line.trim().is_empty() && !line.starts_with(char::is_whitespace));
+
+        Alignment::from_blocks(lines)
+    }
+
+    fn from_blocks<'a>(lines: impl Iterator<Item = &'a str>) -> Result<Alignment, String> {
+        let mut names: Vec<String> = vec![];
+        let mut rows: Vec<String> = vec![];
+
+        for line in lines {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            let (name, row) = match fields[..] {
+                [name, row, ..] => (name, row),
+                _ => return Err(format!("Invalid alignment line: {}", line)),
+            };
+            match names.iter().position(|n| n == name) {
+                Some(index) => rows[index].push_str(row),
+                None => {
+                    names.push(name.into());
+                    rows.push(row.into());
+                }
+            }
+        }
+
+        if rows.is_empty() {
+            return Err("The alignment contains no sequences".into());
+        }
+        if rows.iter().any(|row| row.len() != rows[0].len()) {
+            return Err("The rows of the alignment differ in length".into());
+        }
+
+        let rows = rows
+            .iter()
+            .zip(&names)
+            .map(|(row, name)| {
+                RNASequence::from_alignment_row(row)
+                    .map_err(|_| format!("The sequence {} is invalid", name))
+            })
+            .collect::<Result<Vec<RNASequence>, String>>()?;
+
+        Ok(Alignment { names, rows })
+    }
+
+    pub fn names(&self) -> &[String] {
+        &self.names
+    }
+
+    pub fn rows(&self) -> &[RNASequence] {
+        &self.rows
+    }
+
+    // Number of columns.
+    pub fn len(&self) -> usize {
+        self.rows[0].len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // Consensus structure of all rows folded jointly (`SS_cons`) and its score; `None` if the
+    // constraint cannot be satisfied.
+    pub fn fold(
+        &self,
+        scoring: &dyn PairScoring,
+        minimal_loop_length: usize,
+        constraint: Option<&Constraint>,
+        max_span: Option<usize>,
+    ) -> Result<Option<(String, f64)>, ScoreOverflow> {
+        let scoring = AlignmentScoring::new(self, scoring);
+        // The columns are scored by `scoring`, so any row serves as the folded sequence.
+        let mut matrix_builder = NussinovMatrixBuilder::new(&self.rows[0], minimal_loop_length)
+            .with_scoring(&scoring)
+            .with_max_span(max_span);
+        if let Some(constraint) = constraint {
+            matrix_builder = matrix_builder.with_constraint(constraint);
+        }
+        let mut matrix = matrix_builder.new_matrix();
+        matrix.fill(&matrix_builder)?;
+
+        let structure = match OptimalStructures::new(&matrix, matrix_builder).next() {
+            Some(structure) => structure,
+            None => return Ok(None),
+        };
+        let structure = NussinovPathConverter::new(&self.rows[0]).convert_structure(&structure);
+        let score = matrix.root().value() as f64 / AlignmentScoring::SCALE;
+
+        Ok(Some((structure, score)))
+    }
+}
+
+impl<'a> AlignmentScoring<'a> {
+    pub const SCALE: f64 = 100.0;
+
+    pub fn new(alignment: &'a Alignment, scoring: &'a dyn PairScoring) -> AlignmentScoring<'a> {
+        AlignmentScoring { alignment, scoring }
+    }
+}
+
+impl PairScoring for AlignmentScoring<'_> {
+    fn score(&self, first: char, second: char) -> Option<usize> {
+        let score = self.scoring.score(first, second)? as f64;
+        Some((score * AlignmentScoring::SCALE) as usize)
+    }
+
+    // Score of pairing the columns `i` and `j`: the mean pair score over all rows, plus the mean
+    // number of differing nucleotides between any two rows that both form a valid pair
+    // (compensatory mutations), minus the fraction of rows that cannot pair.
+    fn score_at(&self, _: &RNASequence, i: usize, j: usize) -> Option<usize> {
+        let rows = self.alignment.rows();
+        let mut score = 0;
+        let mut pairs = vec![];
+        for row in rows {
+            if row.is_gap(i) || row.is_gap(j) {
+                continue;
+            }
+            if let Some(s) = self.scoring.score(row[i], row[j]) {
+                score += s;
+                pairs.push((row[i], row[j]));
+            }
+        }
+
+        let mut covariation = 0;
+        for (k, first) in pairs.iter().enumerate() {
+            for second in &pairs[(k + 1)..] {
+                covariation += usize::from(first.0 != second.0) + usize::from(first.1 != second.1);
+            }
+        }
+
+        let n = rows.len() as f64;
+        let combinations = (n * (n - 1.0) / 2.0).max(1.0);
+        let incompatible = (rows.len() - pairs.len()) as f64;
+        let value = score as f64 / n + covariation as f64 / combinations - incompatible / n;
+
+        let value = (value * AlignmentScoring::SCALE).round();
+        if value > 0.0 {
+            return Some(value as usize);
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Alignment, AlignmentScoring};
+    use crate::{
+        nussinov::RNASequence,
+        pair_scoring::{PairScoring, UnitScoring},
+    };
+
+    // The outer columns pair in every row with compensatory changes, the inner ones in two rows.
+    const STOCKHOLM: &str = "# STOCKHOLM 1.0
+#=GF ID fixture
+
+seq1 GCAA
+seq2 CGAA
+seq3 GAAA
+
+seq1 AAGC
+seq2 AACG
+seq3 A-GU
+#=GC SS_cons ((....))
+//
+";
+
+    const CLUSTAL: &str = "CLUSTAL W (1.83) multiple sequence alignment
+
+seq1 GCAAAAGC 8
+seq2 CGAAAACG 8
+seq3 GAAAA-GU 7
+         **
+";
+
+    fn rows(alignment: &Alignment) -> Vec<String> {
+        let rows = alignment.rows().iter();
+        rows.map(|row| (0..row.len()).map(|i| row[i]).collect())
+            .collect()
+    }
+
+    #[test]
+    fn parses_stockholm_and_clustal() {
+        let expected = vec!["GCAAAAGC", "CGAAAACG", "GAAAA-GU"];
+        for content in [STOCKHOLM, CLUSTAL] {
+            let alignment = match content.starts_with('#') {
+                true => Alignment::from_stockholm(content),
+                false => Alignment::from_clustal(content),
+            }
+            .unwrap();
+            assert_eq!(alignment.names(), ["seq1", "seq2", "seq3"]);
+            assert_eq!(rows(&alignment), expected);
+            assert_eq!(alignment.len(), 8);
+        }
+    }
+
+    #[test]
+    fn scores_columns_by_covariation() {
+        let alignment = Alignment::from_stockholm(STOCKHOLM).unwrap();
+        let scoring = AlignmentScoring::new(&alignment, &UnitScoring);
+        let sequence = RNASequence::new("A").unwrap();
+
+        // GC, CG, GU: 3 pairs, 2 + 1 + 2 differing nucleotides over 3 pairs of rows.
+        assert_eq!(scoring.score_at(&sequence, 0, 7), Some(267));
+        // CG, GC and AG: 2 pairs, 2 differing nucleotides, 1 row that cannot pair.
+        assert_eq!(scoring.score_at(&sequence, 1, 6), Some(100));
+        // A gap counts as a row that cannot pair.
+        assert_eq!(scoring.score_at(&sequence, 2, 5), None);
+        assert_eq!(scoring.score_at(&sequence, 0, 6), None);
+
+        let folded = alignment.fold(&UnitScoring, 1, None, None).unwrap();
+        assert_eq!(folded, Some(("((....))".to_string(), 3.67)));
+    }
+
+    #[test]
+    fn rejects_malformed_alignments() {
+        let ragged = "# STOCKHOLM 1.0\nseq1 GCAA\nseq2 GCA\n//\n";
+        assert!(Alignment::from_stockholm(ragged).is_err());
+        assert!(Alignment::from_stockholm("# STOCKHOLM 1.0\nseq1\n//\n").is_err());
+        assert!(Alignment::from_stockholm("# STOCKHOLM 1.0\n//\n").is_err());
+        assert!(Alignment::from_clustal("CLUSTAL W\n\nseq1 GCXA\n").is_err());
+
+        let path = std::env::temp_dir().join("nussinov_unknown_alignment.txt");
+        std::fs::write(&path, ">seq1\nGCAA\n").unwrap();
+        let unknown = Alignment::from_file(path.to_str().unwrap());
+        std::fs::remove_file(&path).unwrap();
+        assert!(unknown.is_err());
+    }
+}
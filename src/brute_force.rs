@@ -0,0 +1,95 @@
+use crate::{
+    matrix::Matrix,
+    matrix_builder::{MatrixBuilder, NussinovMatrixBuilder},
+    nussinov::RNASequence,
+    pair_scoring::WeightedScoring,
+    structure::Structure,
+};
+
+// Sequences short enough to enumerate: a hairpin, repeats with many co-optimal structures,
+// competing helices and sequences too short to pair.
+pub const SEQUENCES: [&str; 5] = ["GGGAAAUCC", "GCAUGCAUGCAU", "AUGGCUACGUAG", "GC", "A"];
+
+// The builders every comparison with the enumeration covers: unit scoring, weighted scoring with
+// a longer minimal loop length and a maximal span.
+pub fn builders(sequence: &RNASequence) -> [NussinovMatrixBuilder<'_>; 3] {
+    [
+        NussinovMatrixBuilder::new(sequence, 1),
+        NussinovMatrixBuilder::new(sequence, 3).with_scoring(&WeightedScoring),
+        NussinovMatrixBuilder::new(sequence, 1).with_max_span(Some(5)),
+    ]
+}
+
+// The filled matrix of the builder and every structure it allows, to compare the two.
+pub fn fill(matrix_builder: &NussinovMatrixBuilder) -> (Matrix, Vec<(Structure, usize)>) {
+    let mut matrix = matrix_builder.new_matrix();
+    matrix_builder.fill(&mut matrix).unwrap();
+    let structures = structures(matrix_builder, matrix.root_position().j);
+    (matrix, structures)
+}
+
+// The structures reaching the highest score.
+pub fn optimal(structures: &[(Structure, usize)]) -> Vec<Structure> {
+    let optimum = structures.iter().map(|(_, score)| *score).max();
+    structures
+        .iter()
+        .filter(|(_, score)| Some(*score) == optimum)
+        .map(|(structure, _)| structure.clone())
+        .collect()
+}
+
+// Every secondary structure the builder allows on `len` nucleotides with its score, found by
+// trying every partner of the first nucleotide of each interval. Only meant for short sequences.
+pub fn structures(matrix_builder: &NussinovMatrixBuilder, len: usize) -> Vec<(Structure, usize)> {
+    let mut structures = vec![];
+    enumerate(
+        matrix_builder,
+        len,
+        &mut vec![(0, len)],
+        &mut vec![],
+        &mut structures,
+    );
+    structures
+}
+
+fn enumerate(
+    matrix_builder: &NussinovMatrixBuilder,
+    len: usize,
+    intervals: &mut Vec<(usize, usize)>,
+    pairs: &mut Vec<(usize, usize, usize)>,
+    structures: &mut Vec<(Structure, usize)>,
+) {
+    let (i, j) = match intervals.pop() {
+        Some((i, j)) if i == j => {
+            enumerate(matrix_builder, len, intervals, pairs, structures);
+            intervals.push((i, j));
+            return;
+        }
+        Some(interval) => interval,
+        None => {
+            let positions: Vec<(usize, usize)> = pairs.iter().map(|(k, l, _)| (*k, *l)).collect();
+            let score = pairs.iter().map(|(_, _, score)| score).sum();
+            structures.push((Structure::from_pairs(len, &positions), score));
+            return;
+        }
+    };
+
+    if matrix_builder.can_be_unpaired(i) {
+        intervals.push((i + 1, j));
+        enumerate(matrix_builder, len, intervals, pairs, structures);
+        intervals.pop();
+    }
+    for k in (i + 1)..j {
+        if let Some(score) = matrix_builder.pair_score(i, k) {
+            intervals.push((k + 1, j));
+            intervals.push((i + 1, k));
+            pairs.push((i, k, score));
+            enumerate(matrix_builder, len, intervals, pairs, structures);
+            pairs.pop();
+            intervals.pop();
+            intervals.pop();
+        }
+    }
+
+    intervals.push((i, j));
+}
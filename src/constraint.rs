@@ -0,0 +1,91 @@
+#[derive(Debug, Clone)]
+pub struct Constraint {
+    symbols: Vec<ConstraintType>,
+    forced_pairs: Vec<(usize, usize)>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConstraintType {
+    Free,
+    Unpaired,
+    Paired(usize),
+    Downstream,
+    Upstream,
+}
+
+#[derive(Debug)]
+pub struct InvalidConstraint;
+
+impl Constraint {
+    pub fn new(constraint: &str) -> Result<Constraint, InvalidConstraint> {
+        let mut symbols = vec![];
+        let mut forced_pairs = vec![];
+        let mut stack = vec![];
+
+        for (index, c) in constraint.trim().chars().enumerate() {
+            let symbol = match c {
+                '.' => ConstraintType::Free,
+                'x' => ConstraintType::Unpaired,
+                '<' => ConstraintType::Downstream,
+                '>' => ConstraintType::Upstream,
+                '(' => {
+                    stack.push(index);
+                    ConstraintType::Free
+                }
+                ')' => {
+                    let opening = stack.pop().ok_or(InvalidConstraint)?;
+                    symbols[opening] = ConstraintType::Paired(index);
+                    forced_pairs.push((opening, index));
+                    ConstraintType::Paired(opening)
+                }
+                _ => return Err(InvalidConstraint),
+            };
+            symbols.push(symbol);
+        }
+
+        if !stack.is_empty() {
+            return Err(InvalidConstraint);
+        }
+
+        Ok(Constraint {
+            symbols,
+            forced_pairs,
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.symbols.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.symbols.is_empty()
+    }
+
+    pub fn can_be_unpaired(&self, i: usize) -> bool {
+        matches!(
+            self.symbols[i],
+            ConstraintType::Free | ConstraintType::Unpaired
+        )
+    }
+
+    // Expects `i < j`.
+    pub fn can_pair(&self, i: usize, j: usize) -> bool {
+        let first = match self.symbols[i] {
+            ConstraintType::Free | ConstraintType::Downstream => true,
+            ConstraintType::Paired(partner) => partner == j,
+            ConstraintType::Unpaired | ConstraintType::Upstream => false,
+        };
+        let second = match self.symbols[j] {
+            ConstraintType::Free | ConstraintType::Upstream => true,
+            ConstraintType::Paired(partner) => partner == i,
+            ConstraintType::Unpaired | ConstraintType::Downstream => false,
+        };
+
+        first
+            && second
+            && !self
+                .forced_pairs
+                .iter()
+                .any(|&(p, q)| (i < p && p < j && j < q) || (p < i && i < q && q < j))
+    }
+}
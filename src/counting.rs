@@ -0,0 +1,219 @@
+use num_bigint::{BigUint, RandBigInt};
+use rand::Rng;
+
+use crate::{
+    matrix::{Matrix, Position, TraceType, Triangle},
+    matrix_builder::{MatrixBuilder, NussinovMatrixBuilder},
+    semiring::Counting,
+    structure::Structure,
+};
+
+// Counts structures with the unambiguous recursion of `NussinovMatrixBuilder::rules`, so every
+// structure is counted exactly once.
+pub struct StructureCounter<'b, 's> {
+    matrix_builder: &'b NussinovMatrixBuilder<'s>,
+}
+
+impl<'b, 's> StructureCounter<'b, 's> {
+    // Expects an unambiguous `matrix_builder`.
+    pub fn new(matrix_builder: &'b NussinovMatrixBuilder<'s>) -> StructureCounter<'b, 's> {
+        StructureCounter { matrix_builder }
+    }
+
+    // Number of distinct optimal structures of the `matrix` filled by the builder and number of
+    // all valid secondary structures of the sequence, both circular if the builder is.
+    pub fn count(&self, matrix: &Matrix) -> (BigUint, BigUint) {
+        let optimal = PathCounts::new(matrix, self.matrix_builder).total().clone();
+
+        // Circular structures are the linear ones except those whose only exterior pair closes a
+        // hairpin across the 3'/5' junction that is too short.
+        let inside = self.matrix_builder.inside(&Counting);
+        let mut total = inside[matrix.root_position().into()].clone();
+        for (i, j) in self.matrix_builder.short_circular_hairpins() {
+            total -= &inside[(i + 1, j)];
+        }
+
+        (optimal, total)
+    }
+}
+
+// Number of paths through the trace graph from every cell of a filled matrix. Drawing each trace
+// with probability proportional to its paths picks every path with the same probability, which
+// for a matrix filled with the unambiguous recursion means every distinct optimal structure.
+pub struct PathCounts<'m> {
+    matrix: &'m Matrix,
+    matrix_builder: &'m dyn MatrixBuilder,
+    counts: Triangle<BigUint>,
+}
+
+impl<'m> PathCounts<'m> {
+    // Expects `matrix` to be filled by `matrix_builder`.
+    pub fn new(matrix: &'m Matrix, matrix_builder: &'m dyn MatrixBuilder) -> PathCounts<'m> {
+        let n = matrix.columns() - 1;
+        let mut path_counts = PathCounts {
+            matrix,
+            matrix_builder,
+            counts: Triangle::banded(BigUint::default(), n + 1, matrix.width()),
+        };
+
+        for d in 0..=n {
+            let last_row = if d <= matrix.width() { n - d } else { 0 };
+            for i in 0..=last_row {
+                let position = Position::from(i, i + d);
+                let count = match position.is_diagonal() {
+                    true => BigUint::from(1u8),
+                    false => matrix_builder
+                        .traces(matrix, position)
+                        .iter()
+                        .map(|t| path_counts.paths(t))
+                        .sum(),
+                };
+                path_counts.counts[(i, i + d)] = count;
+            }
+        }
+
+        path_counts
+    }
+
+    // Number of paths from the root, zero if the matrix admits no structure.
+    pub fn total(&self) -> &BigUint {
+        &self.counts[self.matrix.root_position().into()]
+    }
+
+    // `None` if the matrix admits no structure.
+    pub fn sample<R: Rng>(&self, rng: &mut R) -> Option<Structure> {
+        let root = self.matrix.root_position();
+        if self.total() == &BigUint::default() {
+            return None;
+        }
+
+        let mut pairs = vec![];
+        let mut pending = vec![root];
+        while let Some(position) = pending.pop() {
+            let trace = self.matrix_builder.traces(self.matrix, position);
+            if trace.is_empty() {
+                continue;
+            }
+            let mut r = rng.gen_biguint_below(&self.counts[position.into()]);
+            let trace_type = trace
+                .iter()
+                .find(|t| {
+                    let paths = self.paths(t);
+                    if r < paths {
+                        return true;
+                    }
+                    r -= paths;
+                    false
+                })
+                .unwrap();
+
+            match trace_type {
+                TraceType::Complementary(inner) => {
+                    pairs.push((position.i, position.j - 1));
+                    pending.push(*inner);
+                }
+                TraceType::Unpaired(pos) => pending.push(*pos),
+                TraceType::Decomposition(pos1, pos2) => pending.extend([*pos2, *pos1]),
+                TraceType::Closed(prefix, inner) => {
+                    pairs.push((prefix.j, inner.j));
+                    pending.extend([*inner, *prefix]);
+                }
+                TraceType::Exterior(target) if target.is_diagonal() => {}
+                TraceType::Exterior(target) => {
+                    pairs.push((target.i, target.j - 1));
+                    pending.push(target.get_complementary());
+                }
+                TraceType::Pseudoknot(..) => {
+                    unreachable!("Pseudoknots are traced by `PseudoknotMatrixBuilder::structure`")
+                }
+            }
+        }
+
+        Some(Structure::from_pairs(root.j, &pairs))
+    }
+
+    fn paths(&self, trace_type: &TraceType) -> BigUint {
+        let count = |position: &Position| &self.counts[position.into()];
+        match trace_type {
+            TraceType::Complementary(pos) | TraceType::Unpaired(pos) => count(pos).clone(),
+            TraceType::Decomposition(pos1, pos2) | TraceType::Closed(pos1, pos2) => {
+                count(pos1) * count(pos2)
+            }
+            TraceType::Exterior(target) if target.is_diagonal() => BigUint::from(1u8),
+            TraceType::Exterior(target) => count(&target.get_complementary()).clone(),
+            TraceType::Pseudoknot(..) => {
+                unreachable!("Pseudoknots are traced by `PseudoknotMatrixBuilder::structure`")
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use num_bigint::BigUint;
+
+    use crate::{
+        brute_force, constraint::Constraint, matrix_builder::NussinovMatrixBuilder,
+        nussinov::RNASequence, structure::Structure,
+    };
+
+    use super::StructureCounter;
+
+    // A linear structure stays valid in a circular molecule unless its only exterior pair closes
+    // a hairpin across the 3'/5' junction that is too short.
+    fn is_circular(structure: &Structure, minimal_loop_length: usize) -> bool {
+        let n = structure.len();
+        let pairs = structure.pairs();
+        let exterior: Vec<&(usize, usize)> = pairs
+            .iter()
+            .filter(|(i, j)| !pairs.iter().any(|(k, l)| k < i && j < l))
+            .collect();
+        match exterior[..] {
+            [(i, j)] => i + n - 1 - j >= minimal_loop_length,
+            _ => true,
+        }
+    }
+
+    fn check_counts(matrix_builder: NussinovMatrixBuilder, circular: bool) {
+        let minimal_loop_length = matrix_builder.minimal_loop_length();
+        let matrix_builder = matrix_builder.with_circular(circular);
+        let (matrix, structures) = brute_force::fill(&matrix_builder);
+        let (optimal, total) = StructureCounter::new(&matrix_builder).count(&matrix);
+
+        let scores: Vec<usize> = structures
+            .into_iter()
+            .filter(|(structure, _)| !circular || is_circular(structure, minimal_loop_length))
+            .map(|(_, score)| score)
+            .collect();
+        let best = scores.iter().max().copied();
+        let best_count = scores.iter().filter(|score| Some(**score) == best).count();
+
+        assert_eq!(total, BigUint::from(scores.len()));
+        assert_eq!(optimal, BigUint::from(best_count));
+    }
+
+    #[test]
+    fn counts_match_enumeration() {
+        for sequence in brute_force::SEQUENCES {
+            let sequence = RNASequence::new(sequence).unwrap();
+            for circular in [false, true] {
+                for matrix_builder in brute_force::builders(&sequence) {
+                    check_counts(matrix_builder, circular);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn counts_respect_constraints() {
+        let sequence = RNASequence::new("GGGAAAUCCAAG").unwrap();
+        for constraint in ["<..(..)x....", "x..........x", "...........>"] {
+            let constraint = Constraint::new(constraint).unwrap();
+            for circular in [false, true] {
+                let matrix_builder =
+                    NussinovMatrixBuilder::new(&sequence, 1).with_constraint(&constraint);
+                check_counts(matrix_builder, circular);
+            }
+        }
+    }
+}
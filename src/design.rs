@@ -0,0 +1,351 @@
+use rand::{rngs::StdRng, seq::SliceRandom, Rng, SeedableRng};
+
+use crate::{
+    matrix::{Matrix, ScoreOverflow},
+    matrix_builder::NussinovMatrixBuilder,
+    nussinov::{RNASequence, KT},
+    pair_scoring::{PairScoring, UnitScoring},
+    path_converter::{NussinovPathConverter, PathConverter},
+    structure::Structure,
+    suboptimal::SuboptimalBuilder,
+};
+
+type Cost = (usize, usize, usize);
+
+const NUCLEOTIDES: [char; 4] = ['A', 'U', 'G', 'C'];
+
+// Number of co-optimal structures the oracle enumerates per candidate.
+const MAX_OPTIMAL_STRUCTURES: usize = 100;
+
+// Mutations per nucleotide without improvement after which the walk restarts.
+const MAX_STAGNATION: usize = 20;
+
+// Inverse folding by adaptive walk: starting from a random sequence compatible with the target,
+// single positions (or both partners of a target pair) are mutated and a mutation is kept
+// whenever the candidate is at least as close to folding into the target.
+pub struct Designer {
+    target: Structure,
+    minimal_loop_length: usize,
+    scoring: Box<dyn PairScoring>,
+    min_gc: f64,
+    max_gc: f64,
+    forbidden_motifs: Vec<Vec<char>>,
+    unique: bool,
+    iterations: usize,
+}
+
+// The best sequence found together with its optimal structures.
+pub struct Design {
+    pub sequence: String,
+    pub structures: Vec<String>,
+    pub solved: bool,
+}
+
+#[derive(Debug)]
+pub struct InvalidStructure;
+
+impl Designer {
+    pub fn new(target: &str, minimal_loop_length: usize) -> Result<Designer, InvalidStructure> {
+        let target = Structure::from_dot_bracket(target).ok_or(InvalidStructure)?;
+        // Pseudoknotted targets could never be the optimal nested structure.
+        if target.has_crossing_pairs()
+            || target
+                .pairs()
+                .iter()
+                .any(|(i, j)| i + minimal_loop_length >= *j)
+        {
+            return Err(InvalidStructure);
+        }
+
+        Ok(Designer {
+            target,
+            minimal_loop_length,
+            scoring: Box::new(UnitScoring),
+            min_gc: 0.0,
+            max_gc: 1.0,
+            forbidden_motifs: vec![],
+            unique: false,
+            iterations: 10000,
+        })
+    }
+
+    pub fn with_scoring(mut self, scoring: Box<dyn PairScoring>) -> Designer {
+        self.scoring = scoring;
+        self
+    }
+
+    // Bounds for the fraction of G and C nucleotides in the designed sequence.
+    pub fn with_gc_content(mut self, min_gc: f64, max_gc: f64) -> Designer {
+        self.min_gc = min_gc;
+        self.max_gc = max_gc;
+        self
+    }
+
+    pub fn with_forbidden_motifs(mut self, motifs: &[String]) -> Designer {
+        self.forbidden_motifs = motifs
+            .iter()
+            .map(|motif| motif.to_uppercase().chars().collect())
+            .filter(|motif: &Vec<char>| !motif.is_empty())
+            .collect();
+        self
+    }
+
+    // Require the target to be the only optimal structure instead of one of them.
+    pub fn with_unique(mut self, unique: bool) -> Designer {
+        self.unique = unique;
+        self
+    }
+
+    pub fn with_iterations(mut self, iterations: usize) -> Designer {
+        self.iterations = iterations;
+        self
+    }
+
+    pub fn design(&self, seed: u64) -> Result<Design, ScoreOverflow> {
+        let mut rng = StdRng::seed_from_u64(seed);
+        let pairs: Vec<(char, char)> = NUCLEOTIDES
+            .iter()
+            .flat_map(|a| NUCLEOTIDES.iter().map(move |b| (*a, *b)))
+            .filter(|(a, b)| self.scoring.score(*a, *b).is_some())
+            .collect();
+
+        let mut sequence = self.initial_sequence(&pairs, &mut rng);
+        let (mut cost, mut structures) = self.evaluate(&sequence)?;
+        let mut best = (cost, sequence.clone(), structures.clone());
+        let mut stagnation = 0;
+
+        for _ in 0..self.iterations {
+            if cost == (0, 0, 0) || sequence.is_empty() {
+                break;
+            }
+            // Escaping some local minima takes several simultaneous mutations, so the walk restarts
+            // once it stopped improving for a while.
+            if stagnation > MAX_STAGNATION * sequence.len() {
+                sequence = self.initial_sequence(&pairs, &mut rng);
+                (cost, structures) = self.evaluate(&sequence)?;
+                stagnation = 0;
+            }
+
+            let defects = self.defects(&structures);
+            let i = match defects.choose(&mut rng) {
+                Some(i) if cost.0 == 0 => *i,
+                _ => rng.gen_range(0..sequence.len()),
+            };
+            let mut candidate = sequence.clone();
+            self.mutate(&mut candidate, i, &pairs, &mut rng);
+
+            let (candidate_cost, candidate_structures) = self.evaluate(&candidate)?;
+            stagnation = if candidate_cost < cost {
+                0
+            } else {
+                stagnation + 1
+            };
+            if candidate_cost <= cost {
+                sequence = candidate;
+                cost = candidate_cost;
+                structures = candidate_structures;
+            }
+            if cost < best.0 {
+                best = (cost, sequence.clone(), structures.clone());
+            }
+        }
+
+        let (cost, sequence, structures) = best;
+        let sequence: String = sequence.into_iter().collect();
+        let rna_sequence = RNASequence::new(&sequence).unwrap();
+        let path_converter = NussinovPathConverter::new(&rna_sequence);
+        Ok(Design {
+            structures: structures
+                .iter()
+                .map(|structure| path_converter.convert_structure(structure))
+                .collect(),
+            sequence,
+            solved: cost == (0, 0, 0),
+        })
+    }
+
+    // Unpaired positions start out as adenines, which pair with the fewest partners.
+    fn initial_sequence<R: Rng>(&self, pairs: &[(char, char)], rng: &mut R) -> Vec<char> {
+        let mut sequence = vec!['A'; self.target.len()];
+        for (i, _) in self.target.pairs() {
+            self.mutate(&mut sequence, i, pairs, rng);
+        }
+        sequence
+    }
+
+    // Assigns a random nucleotide to `i`, or a random pair to `i` and its partner in the target.
+    fn mutate<R: Rng>(&self, sequence: &mut [char], i: usize, pairs: &[(char, char)], rng: &mut R) {
+        match (self.target.partner(i), pairs.choose(rng)) {
+            (Some(j), Some((a, b))) => {
+                let (first, second) = if i < j { (i, j) } else { (j, i) };
+                sequence[first] = *a;
+                sequence[second] = *b;
+            }
+            _ => sequence[i] = *NUCLEOTIDES.choose(rng).unwrap(),
+        }
+    }
+
+    // Cost of a candidate, compared lexicographically: violated sequence constraints, the
+    // base-pair distance of the closest optimal structure to the target and, for unique designs,
+    // -kT ln P(target) in the Boltzmann ensemble, which only vanishes without competitors.
+    fn evaluate(&self, sequence: &[char]) -> Result<(Cost, Vec<Structure>), ScoreOverflow> {
+        let rna_sequence = RNASequence::new(&sequence.iter().collect::<String>()).unwrap();
+        let matrix_builder = NussinovMatrixBuilder::new(&rna_sequence, self.minimal_loop_length)
+            .with_scoring(self.scoring.as_ref());
+        let mut matrix = Matrix::new(rna_sequence.len());
+        matrix.fill(&matrix_builder)?;
+
+        let structures: Vec<Structure> =
+            SuboptimalBuilder::new(&matrix_builder, 0, MAX_OPTIMAL_STRUCTURES)
+                .build(&matrix)
+                .into_iter()
+                .map(|(structure, _)| structure)
+                .collect();
+
+        let distance = structures
+            .iter()
+            .map(|s| s.distance(&self.target))
+            .min()
+            .unwrap_or(0);
+        let competitors = structures.iter().filter(|s| **s != self.target).count();
+
+        let defect = if self.unique && competitors > 0 {
+            let free_energy = matrix_builder
+                .partition_function(&matrix, KT)
+                .ensemble_free_energy();
+            let score: usize = self
+                .target
+                .pairs()
+                .iter()
+                .filter_map(|(i, j)| matrix_builder.pair_score(*i, *j))
+                .sum();
+            // In thousandths, so that costs stay totally ordered.
+            ((-(score as f64) - free_energy).max(0.0) * 1000.0).round() as usize + 1
+        } else {
+            0
+        };
+
+        Ok(((self.violations(sequence), distance, defect), structures))
+    }
+
+    // Positions whose partner differs from the target in an optimal structure that counts
+    // towards the cost.
+    fn defects(&self, structures: &[Structure]) -> Vec<usize> {
+        let counted: Vec<&Structure> = if self.unique {
+            structures.iter().collect()
+        } else {
+            structures
+                .iter()
+                .min_by_key(|s| s.distance(&self.target))
+                .into_iter()
+                .collect()
+        };
+
+        (0..self.target.len())
+            .filter(|i| {
+                counted
+                    .iter()
+                    .any(|s| s.partner(*i) != self.target.partner(*i))
+            })
+            .collect()
+    }
+
+    fn violations(&self, sequence: &[char]) -> usize {
+        let len = sequence.len() as f64;
+        let gc = sequence.iter().filter(|c| **c == 'G' || **c == 'C').count();
+        let min_gc = (self.min_gc * len).ceil() as usize;
+        let max_gc = (self.max_gc * len).floor() as usize;
+        let gc_violation = min_gc.saturating_sub(gc) + gc.saturating_sub(max_gc);
+
+        let motif_violations: usize = self
+            .forbidden_motifs
+            .iter()
+            .map(|motif| {
+                sequence
+                    .windows(motif.len())
+                    .filter(|window| window == motif)
+                    .count()
+            })
+            .sum();
+
+        gc_violation + motif_violations
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Designer;
+    use crate::nussinov::Nussinov;
+
+    const TARGET: &str = "((((...))))..((((...))))";
+
+    fn gc_content(sequence: &str) -> f64 {
+        let gc = sequence.chars().filter(|c| *c == 'G' || *c == 'C').count();
+        gc as f64 / sequence.len() as f64
+    }
+
+    #[test]
+    fn designs_are_reproducible_from_the_seed() {
+        let designer = Designer::new(TARGET, 1).unwrap();
+        assert_eq!(
+            designer.design(7).unwrap().sequence,
+            designer.design(7).unwrap().sequence
+        );
+    }
+
+    #[test]
+    fn designed_sequence_folds_into_the_target() {
+        let design = Designer::new(TARGET, 1).unwrap().design(1).unwrap();
+        assert!(design.solved);
+        let structures: Vec<String> = Nussinov::new(&design.sequence, 1)
+            .structures()
+            .unwrap()
+            .collect();
+        assert!(structures.contains(&TARGET.to_string()));
+
+        let design = Designer::new(TARGET, 1)
+            .unwrap()
+            .with_unique(true)
+            .design(1)
+            .unwrap();
+        assert!(design.solved);
+        let structures: Vec<String> = Nussinov::new(&design.sequence, 1)
+            .structures()
+            .unwrap()
+            .collect();
+        assert_eq!(structures, vec![TARGET.to_string()]);
+    }
+
+    #[test]
+    fn gc_content_stays_within_bounds() {
+        for (min_gc, max_gc) in [(0.0, 0.3), (0.4, 0.6), (0.7, 1.0)] {
+            let design = Designer::new(TARGET, 1)
+                .unwrap()
+                .with_gc_content(min_gc, max_gc)
+                .design(3)
+                .unwrap();
+            assert!(design.solved);
+            let gc = gc_content(&design.sequence);
+            assert!(
+                min_gc <= gc && gc <= max_gc,
+                "{} has GC content {}",
+                design.sequence,
+                gc
+            );
+        }
+    }
+
+    #[test]
+    fn forbidden_motifs_are_avoided() {
+        let motifs = ["GG".to_string(), "cc".to_string(), "AAA".to_string()];
+        let design = Designer::new(TARGET, 1)
+            .unwrap()
+            .with_forbidden_motifs(&motifs)
+            .design(5)
+            .unwrap();
+        assert!(design.solved);
+        for motif in ["GG", "CC", "AAA"] {
+            assert!(!design.sequence.contains(motif), "{}", design.sequence);
+        }
+    }
+}
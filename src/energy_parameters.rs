@@ -0,0 +1,128 @@
+// Simplified nearest-neighbour parameters (Turner 2004) in dcal/mol.
+
+pub const INF: i32 = 10_000_000;
+
+pub const MAX_LOOP: usize = 30;
+
+pub const MIN_HAIRPIN: usize = 3;
+
+pub const MULTILOOP_CLOSING: i32 = 340;
+pub const MULTILOOP_UNPAIRED: i32 = 0;
+pub const MULTILOOP_BRANCH: i32 = 40;
+
+const TERMINAL_AU: i32 = 50;
+const NINIO: i32 = 60;
+const NINIO_MAX: i32 = 300;
+const LXC: f64 = 107.856;
+
+// Pair types: CG, GC, GU, UG, AU, UA
+const STACK: [[i32; 6]; 6] = [
+    [-240, -330, -210, -140, -210, -210],
+    [-330, -340, -250, -150, -220, -240],
+    [-210, -250, 130, -50, -140, -130],
+    [-140, -150, -50, 30, -60, -100],
+    [-210, -220, -140, -60, -110, -90],
+    [-210, -240, -130, -100, -90, -130],
+];
+
+const HAIRPIN: [i32; MAX_LOOP + 1] = [
+    INF, INF, INF, 540, 560, 570, 540, 600, 550, 640, 650, 660, 670, 678, 686, 694, 701, 707, 713,
+    719, 725, 730, 735, 740, 744, 749, 753, 757, 761, 765, 769,
+];
+
+const BULGE: [i32; MAX_LOOP + 1] = [
+    INF, 380, 280, 320, 360, 400, 440, 459, 470, 480, 490, 500, 510, 519, 527, 534, 541, 548, 554,
+    560, 565, 571, 576, 580, 585, 589, 594, 598, 602, 605, 609,
+];
+
+const INTERIOR: [i32; MAX_LOOP + 1] = [
+    INF, INF, 50, 160, 110, 200, 200, 210, 230, 240, 250, 260, 270, 280, 290, 290, 300, 310, 310,
+    320, 330, 330, 340, 340, 350, 350, 350, 360, 360, 370, 370,
+];
+
+pub fn pair_type(first: char, second: char) -> Option<usize> {
+    match (first, second) {
+        ('C', 'G') => Some(0),
+        ('G', 'C') => Some(1),
+        ('G', 'U') => Some(2),
+        ('U', 'G') => Some(3),
+        ('A', 'U') => Some(4),
+        ('U', 'A') => Some(5),
+        _ => None,
+    }
+}
+
+pub fn terminal_penalty(pair_type: usize) -> i32 {
+    if pair_type >= 2 {
+        return TERMINAL_AU;
+    }
+    0
+}
+
+pub fn hairpin(pair_type: usize, size: usize) -> i32 {
+    if size < MIN_HAIRPIN {
+        return INF;
+    }
+
+    let initiation = extrapolate(&HAIRPIN, size);
+    if size == MIN_HAIRPIN {
+        return initiation + terminal_penalty(pair_type);
+    }
+    initiation
+}
+
+// `outer` is the type of the closing pair (i, j), `inner` the type of the reversed inner pair (l, k).
+pub fn interior(outer: usize, inner: usize, left: usize, right: usize) -> i32 {
+    let size = left + right;
+    if size > MAX_LOOP {
+        return INF;
+    }
+
+    if size == 0 {
+        return STACK[outer][inner];
+    }
+
+    if left == 0 || right == 0 {
+        if size == 1 {
+            return BULGE[1] + STACK[outer][inner];
+        }
+        return BULGE[size] + terminal_penalty(outer) + terminal_penalty(inner);
+    }
+
+    let asymmetry = (left as i32 - right as i32).abs() * NINIO;
+    INTERIOR[size] + asymmetry.min(NINIO_MAX) + terminal_penalty(outer) + terminal_penalty(inner)
+}
+
+fn extrapolate(table: &[i32; MAX_LOOP + 1], size: usize) -> i32 {
+    if size <= MAX_LOOP {
+        return table[size];
+    }
+    table[MAX_LOOP] + (LXC * (size as f64 / MAX_LOOP as f64).ln()).round() as i32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{hairpin, interior, pair_type};
+
+    #[test]
+    fn loop_energies_match_the_tables() {
+        let (cg, gc, gu) = (
+            pair_type('C', 'G').unwrap(),
+            pair_type('G', 'C').unwrap(),
+            pair_type('G', 'U').unwrap(),
+        );
+
+        // Triloops closed by GU pay the terminal penalty, longer hairpins do not.
+        assert_eq!(hairpin(gc, 3), 540);
+        assert_eq!(hairpin(gu, 3), 590);
+        assert_eq!(hairpin(gu, 4), 560);
+        assert_eq!(hairpin(gc, 2), super::INF);
+        // 5'-GG-3' / 3'-CC-5': the inner pair is read reversed, as CG.
+        assert_eq!(interior(gc, cg, 0, 0), -330);
+        // A single bulged nucleotide keeps the stack.
+        assert_eq!(interior(gc, cg, 1, 0), 380 - 330);
+        // A 1×2 interior loop closed by GU and CG: the loop, one asymmetry and one penalty.
+        assert_eq!(interior(gu, cg, 1, 2), 160 + 60 + 50);
+        assert_eq!(interior(gc, cg, 20, 11), super::INF);
+    }
+}
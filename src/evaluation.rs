@@ -0,0 +1,157 @@
+use crate::{design::InvalidStructure, structure::Structure};
+
+// Accuracy of a predicted structure with respect to a reference structure.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Comparison {
+    pub distance: usize,
+    pub sensitivity: f64,
+    pub ppv: f64,
+    pub f1: f64,
+    pub mcc: f64,
+}
+
+impl Comparison {
+    // Compares two dot-bracket structures of equal length; strand breaks (`&`) are ignored. With
+    // `slip`, a predicted pair (i, j) also matches the reference pairs (i ± 1, j) and (i, j ± 1).
+    // The base-pair distance always counts exact pairs. Undefined ratios are reported as 0.
+    pub fn new(
+        predicted: &str,
+        reference: &str,
+        slip: bool,
+    ) -> Result<Comparison, InvalidStructure> {
+        let predicted = Comparison::parse(predicted)?;
+        let reference = Comparison::parse(reference)?;
+        if predicted.len() != reference.len() {
+            return Err(InvalidStructure);
+        }
+
+        let predicted_pairs = predicted.pairs();
+        let reference_pairs = reference.pairs();
+        let matches = |(i, j): (usize, usize), other: &Structure| {
+            other.partner(i) == Some(j)
+                || (slip
+                    && [(i + 1, j), (i, j + 1)]
+                        .into_iter()
+                        .chain(i.checked_sub(1).map(|i| (i, j)))
+                        .chain(j.checked_sub(1).map(|j| (i, j)))
+                        .any(|(k, l)| k < other.len() && other.partner(k) == Some(l)))
+        };
+
+        let true_positives = predicted_pairs
+            .iter()
+            .filter(|pair| matches(**pair, &reference))
+            .count();
+        let found = reference_pairs
+            .iter()
+            .filter(|pair| matches(**pair, &predicted))
+            .count();
+        let false_positives = predicted_pairs.len() - true_positives;
+        let false_negatives = reference_pairs.len() - found;
+
+        let sensitivity = ratio(found as f64, reference_pairs.len() as f64);
+        let ppv = ratio(true_positives as f64, predicted_pairs.len() as f64);
+        let f1 = ratio(2.0 * sensitivity * ppv, sensitivity + ppv);
+
+        let n = predicted.len();
+        let candidates = n * n.saturating_sub(1) / 2;
+        let true_negatives =
+            candidates.saturating_sub(true_positives + false_positives + false_negatives) as f64;
+        let (tp, fp, fn_) = (
+            true_positives as f64,
+            false_positives as f64,
+            false_negatives as f64,
+        );
+        let mcc = ratio(
+            tp * true_negatives - fp * fn_,
+            ((tp + fp) * (tp + fn_) * (true_negatives + fp) * (true_negatives + fn_)).sqrt(),
+        );
+
+        Ok(Comparison {
+            distance: predicted.distance(&reference),
+            sensitivity,
+            ppv,
+            f1,
+            mcc,
+        })
+    }
+
+    fn parse(dot_bracket: &str) -> Result<Structure, InvalidStructure> {
+        let dot_bracket: String = dot_bracket.chars().filter(|c| *c != '&').collect();
+        Structure::from_dot_bracket(&dot_bracket).ok_or(InvalidStructure)
+    }
+}
+
+fn ratio(numerator: f64, denominator: f64) -> f64 {
+    if denominator > 0.0 {
+        return numerator / denominator;
+    }
+    0.0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Comparison;
+
+    fn assert_close(actual: f64, expected: f64) {
+        assert!(
+            (actual - expected).abs() < 1e-9,
+            "{} is not {}",
+            actual,
+            expected
+        );
+    }
+
+    fn assert_scores(comparison: Comparison, expected: [f64; 4]) {
+        let [sensitivity, ppv, f1, mcc] = expected;
+        assert_close(comparison.sensitivity, sensitivity);
+        assert_close(comparison.ppv, ppv);
+        assert_close(comparison.f1, f1);
+        assert_close(comparison.mcc, mcc);
+    }
+
+    #[test]
+    fn scores_a_partial_prediction() {
+        // One of two reference pairs is found and nothing else is predicted. Of the 15 candidate
+        // pairs of 6 nucleotides, 13 are true negatives.
+        let comparison = Comparison::new("(....)", "((..))", false).unwrap();
+        assert_eq!(comparison.distance, 1);
+        let mcc = 13.0 / (1.0f64 * 2.0 * 13.0 * 14.0).sqrt();
+        assert_scores(comparison, [0.5, 1.0, 2.0 / 3.0, mcc]);
+    }
+
+    #[test]
+    fn slip_matches_pairs_shifted_by_one() {
+        // Without slip the prediction is all wrong: 1 false positive, 2 false negatives and 18
+        // true negatives among 21 candidates.
+        let comparison = Comparison::new("(....).", "((...))", false).unwrap();
+        let mcc = -2.0 / (1.0f64 * 2.0 * 19.0 * 20.0).sqrt();
+        assert_scores(comparison, [0.0, 0.0, 0.0, mcc]);
+
+        // (0, 5) is one off from both (0, 6) and (1, 5).
+        let comparison = Comparison::new("(....).", "((...))", true).unwrap();
+        assert_eq!(comparison.distance, 3);
+        assert_scores(comparison, [1.0, 1.0, 1.0, 1.0]);
+
+        let comparison = Comparison::new("(...)..", "(.....)", true).unwrap();
+        assert_scores(
+            comparison,
+            [0.0, 0.0, 0.0, -1.0 / (1.0f64 * 20.0 * 20.0).sqrt()],
+        );
+    }
+
+    #[test]
+    fn empty_structures_score_zero() {
+        for (predicted, reference) in [("(..)", "...."), ("....", "(..)"), ("....", "....")] {
+            for slip in [false, true] {
+                let comparison = Comparison::new(predicted, reference, slip).unwrap();
+                assert_scores(comparison, [0.0, 0.0, 0.0, 0.0]);
+            }
+        }
+    }
+
+    #[test]
+    fn rejects_structures_of_different_lengths() {
+        assert!(Comparison::new("(..)", "(...)", false).is_err());
+        assert!(Comparison::new("(..", "...", false).is_err());
+    }
+}
@@ -0,0 +1,251 @@
+use std::collections::{BinaryHeap, HashMap, HashSet};
+
+use crate::{
+    matrix::{Matrix, Position},
+    matrix_builder::NussinovMatrixBuilder,
+    structure::Structure,
+    suboptimal::SuboptimalStructures,
+};
+
+// Lazy k-best parsing (Huang & Chiang, 2005) over the unambiguous decomposition: the last
+// nucleotide of an interval is either unpaired or pairs with some `k`. Every derivation is a
+// distinct structure, and the ranked derivations of a cell are only computed when a larger
+// interval asks for them, so the work grows with `k` instead of with the energy window.
+pub struct KBestBuilder<'b, 's> {
+    matrix_builder: &'b NussinovMatrixBuilder<'s>,
+    k: usize,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+enum Edge {
+    Unpaired,
+    // Pairs `k` with the last nucleotide of the interval.
+    Closed(usize),
+}
+
+// A derivation of a cell: its edge and the rank of the derivation used for every tail cell.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+struct Derivation {
+    score: usize,
+    edge: Edge,
+    ranks: [usize; 2],
+}
+
+#[derive(Default)]
+struct Cell {
+    ranked: Vec<Derivation>,
+    candidates: BinaryHeap<Derivation>,
+    seen: HashSet<(Edge, [usize; 2])>,
+}
+
+struct Search<'b, 's, 'm> {
+    matrix_builder: &'b NussinovMatrixBuilder<'s>,
+    matrix: &'m Matrix,
+    cells: HashMap<Position, Cell>,
+}
+
+impl<'b, 's> KBestBuilder<'b, 's> {
+    pub fn new(matrix_builder: &'b NussinovMatrixBuilder<'s>, k: usize) -> KBestBuilder<'b, 's> {
+        KBestBuilder { matrix_builder, k }
+    }
+
+    // The `k` best distinct structures of the linearly filled `matrix`, best first.
+    pub fn build(&self, matrix: &Matrix) -> SuboptimalStructures {
+        let root = matrix.root_position();
+        if !matrix[root].is_feasible() {
+            return vec![];
+        }
+
+        let mut search = Search {
+            matrix_builder: self.matrix_builder,
+            matrix,
+            cells: HashMap::new(),
+        };
+        (0..self.k)
+            .map_while(|rank| {
+                let score = search.kth_best(root, rank)?.score;
+                let mut pairs = vec![];
+                search.collect_pairs(root, rank, &mut pairs);
+                Some((Structure::from_pairs(root.j, &pairs), score))
+            })
+            .collect()
+    }
+}
+
+impl Search<'_, '_, '_> {
+    // The derivation of rank `k` (0-based) of a cell, if it has that many.
+    fn kth_best(&mut self, position: Position, k: usize) -> Option<Derivation> {
+        if position.is_diagonal() {
+            return (k == 0).then_some(Derivation {
+                score: 0,
+                edge: Edge::Unpaired,
+                ranks: [0, 0],
+            });
+        }
+        if !self.cells.contains_key(&position) {
+            self.initialize(position);
+        }
+
+        loop {
+            let cell = &self.cells[&position];
+            if let Some(derivation) = cell.ranked.get(k) {
+                return Some(*derivation);
+            }
+            // The successors of the last ranked derivation are only enumerated once the next
+            // rank is asked for.
+            if let Some(last) = cell.ranked.last().copied() {
+                self.push_successors(position, last);
+            }
+            let cell = self.cells.get_mut(&position).unwrap();
+            let derivation = cell.candidates.pop()?;
+            cell.ranked.push(derivation);
+        }
+    }
+
+    // Queues the best derivation of every edge, whose tails all use their best derivations.
+    fn initialize(&mut self, position: Position) {
+        let mut cell = Cell::default();
+        for edge in self.edges(position) {
+            if let Some(score) = self.score(position, edge, [0, 0]) {
+                cell.seen.insert((edge, [0, 0]));
+                cell.candidates.push(Derivation {
+                    score,
+                    edge,
+                    ranks: [0, 0],
+                });
+            }
+        }
+        self.cells.insert(position, cell);
+    }
+
+    fn push_successors(&mut self, position: Position, derivation: Derivation) {
+        let tails = self.tails(position, derivation.edge);
+        for t in 0..tails.len() {
+            let mut ranks = derivation.ranks;
+            ranks[t] += 1;
+            if self.cells[&position]
+                .seen
+                .contains(&(derivation.edge, ranks))
+            {
+                continue;
+            }
+
+            let score = match self.score(position, derivation.edge, ranks) {
+                Some(score) => score,
+                None => continue,
+            };
+
+            let cell = self.cells.get_mut(&position).unwrap();
+            cell.seen.insert((derivation.edge, ranks));
+            cell.candidates.push(Derivation {
+                score,
+                edge: derivation.edge,
+                ranks,
+            });
+        }
+    }
+
+    // Score of an edge whose tails use the derivations of the given ranks, if they all exist.
+    fn score(&mut self, position: Position, edge: Edge, ranks: [usize; 2]) -> Option<usize> {
+        let mut score = self.weight(position, edge);
+        for (tail, rank) in self.tails(position, edge).into_iter().zip(ranks) {
+            score += self.kth_best(tail, rank)?.score;
+        }
+        Some(score)
+    }
+
+    fn edges(&self, position: Position) -> Vec<Edge> {
+        let (i, j) = (position.i, position.j - 1);
+        let mut edges = vec![];
+        if self.matrix_builder.can_be_unpaired(j) && self.matrix[Position::from(i, j)].is_feasible()
+        {
+            edges.push(Edge::Unpaired);
+        }
+        for k in i..j {
+            let edge = Edge::Closed(k);
+            if self.matrix_builder.pair_score(k, j).is_some()
+                && self
+                    .tails(position, edge)
+                    .iter()
+                    .all(|tail| self.matrix[*tail].is_feasible())
+            {
+                edges.push(edge);
+            }
+        }
+        edges
+    }
+
+    fn tails(&self, position: Position, edge: Edge) -> Vec<Position> {
+        let (i, j) = (position.i, position.j - 1);
+        match edge {
+            Edge::Unpaired => vec![Position::from(i, j)],
+            Edge::Closed(k) => vec![Position::from(i, k), Position::from(k + 1, j)],
+        }
+    }
+
+    fn weight(&self, position: Position, edge: Edge) -> usize {
+        match edge {
+            Edge::Unpaired => 0,
+            Edge::Closed(k) => self.matrix_builder.pair_score(k, position.j - 1).unwrap(),
+        }
+    }
+
+    // Expects the derivation to be ranked already.
+    fn collect_pairs(&self, position: Position, k: usize, pairs: &mut Vec<(usize, usize)>) {
+        if position.is_diagonal() {
+            return;
+        }
+        let derivation = self.cells[&position].ranked[k];
+        if let Edge::Closed(l) = derivation.edge {
+            pairs.push((l, position.j - 1));
+        }
+        let tails = self.tails(position, derivation.edge);
+        for (tail, rank) in tails.into_iter().zip(derivation.ranks) {
+            self.collect_pairs(tail, rank, pairs);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use crate::{brute_force, matrix_builder::NussinovMatrixBuilder, nussinov::RNASequence};
+
+    use super::KBestBuilder;
+
+    // The k best structures have to be distinct, valid, best first and score like the k best
+    // enumerated structures.
+    fn check_ranking(matrix_builder: &NussinovMatrixBuilder, k: usize) {
+        let (matrix, structures) = brute_force::fill(matrix_builder);
+        let ranked = KBestBuilder::new(matrix_builder, k).build(&matrix);
+
+        let mut scores: Vec<usize> = structures.iter().map(|(_, score)| *score).collect();
+        scores.sort_unstable_by(|a, b| b.cmp(a));
+        scores.truncate(k);
+        let ranked_scores: Vec<usize> = ranked.iter().map(|(_, score)| *score).collect();
+        assert_eq!(ranked_scores, scores);
+
+        let distinct: HashSet<_> = ranked.iter().map(|(structure, _)| structure).collect();
+        assert_eq!(distinct.len(), ranked.len(), "structures are ranked twice");
+        for entry in &ranked {
+            assert!(
+                structures.contains(entry),
+                "{:?} is not a valid structure",
+                entry
+            );
+        }
+    }
+
+    #[test]
+    fn ranking_matches_enumeration() {
+        for sequence in brute_force::SEQUENCES {
+            let sequence = RNASequence::new(sequence).unwrap();
+            for matrix_builder in brute_force::builders(&sequence) {
+                for k in [1, 2, 5, 20, 1000] {
+                    check_ranking(&matrix_builder, k);
+                }
+            }
+        }
+    }
+}
@@ -1,6 +1,23 @@
+pub mod adp;
+pub mod alignment;
+#[cfg(test)]
+mod brute_force;
+pub mod constraint;
+mod counting;
+pub mod design;
+mod energy_parameters;
+pub mod evaluation;
+mod kbest;
 mod matrix;
-mod matrix_builder;
+pub mod matrix_builder;
 pub mod nussinov;
+pub mod pair_scoring;
 mod path_converter;
+pub mod scan;
+pub mod semiring;
 pub mod settings;
+pub mod shape;
+mod structure;
+mod suboptimal;
 mod traceback_paths;
+pub mod training;
@@ -1,22 +1,457 @@
-use std::process::exit;
+use std::{
+    fmt::Display,
+    fs::File,
+    io::{BufRead, BufReader},
+    process::exit,
+};
 
 use clap::Parser;
-use nussinov_cli::{nussinov::Nussinov, settings};
+use nussinov_cli::{
+    adp::{Algebra, Count, DotBracket, Grammar, Product, Score, Shape},
+    alignment::Alignment,
+    constraint::Constraint,
+    design::Designer,
+    evaluation::Comparison,
+    nussinov::{Algorithm, InvalidSequence, Nussinov},
+    pair_scoring::{PairScoring, TableScoring, UnitScoring, WeightedScoring},
+    scan::{LocalStructure, WindowScanner},
+    settings::{self, Command},
+    shape::ShapeData,
+    training::{GrammarParameters, Trainer, TrainingExample},
+};
 
 fn main() {
     let settings = settings::Settings::parse();
 
-    let sequence;
-    if settings.sequence.is_some() {
-        sequence = settings.sequence.unwrap();
-    } else if settings.file.is_some() {
-        let path = settings.file.unwrap();
-        sequence = std::fs::read_to_string(path).expect("File could not be read");
+    let scoring: Box<dyn PairScoring> = match settings.scoring.as_str() {
+        "unit" => Box::new(UnitScoring),
+        "weighted" => Box::new(WeightedScoring),
+        path => match load_scoring(path) {
+            Ok(scoring) => scoring,
+            Err(e) => {
+                println!("{}", e);
+                exit(0);
+            }
+        },
+    };
+
+    if let Some(Command::Design(design)) = &settings.command {
+        let designer = match Designer::new(&design.structure, 1) {
+            Ok(designer) => designer,
+            Err(_) => {
+                println!("The given target structure is invalid");
+                exit(0);
+            }
+        };
+        let designer = designer
+            .with_scoring(scoring)
+            .with_gc_content(design.min_gc, design.max_gc)
+            .with_forbidden_motifs(&design.forbidden_motifs)
+            .with_unique(design.unique)
+            .with_iterations(design.iterations);
+
+        let seed = design.seed.unwrap_or_else(rand::random);
+        let result = filled(designer.design(seed));
+        if !result.solved {
+            println!("No sequence folding into the target was found; closest candidate:");
+        }
+        println!("{}", result.sequence);
+        for structure in result.structures {
+            println!("{}", structure);
+        }
+        exit(0);
+    }
+
+    if let Some(Command::Train(train)) = &settings.command {
+        let content = std::fs::read_to_string(&train.input).expect("File could not be read");
+        let examples = match TrainingExample::from_fasta(&content) {
+            Ok(examples) => examples,
+            Err(e) => {
+                println!("{}", e);
+                exit(0);
+            }
+        };
+        let trained = Trainer::new(1)
+            .with_iterations(train.iterations)
+            .with_tolerance(train.tolerance)
+            .train(&examples);
+        let (parameters, log_likelihood) = match trained {
+            Ok(trained) => trained,
+            Err(e) => {
+                println!("{}", e);
+                exit(0);
+            }
+        };
+
+        if std::fs::write(&train.output, parameters.to_string()).is_err() {
+            println!("The parameters could not be written to {}", train.output);
+            exit(0);
+        }
+        println!("Log-likelihood: {:.3}", log_likelihood);
+        exit(0);
+    }
+
+    if let Some(Command::Eval(eval)) = &settings.command {
+        if !eval.predicted.is_empty() {
+            compare(&eval.predicted, &eval.reference, eval.slip);
+            exit(0);
+        }
+    }
+
+    if let Some(path) = settings.alignment {
+        let alignment = match Alignment::from_file(&path) {
+            Ok(alignment) => alignment,
+            Err(e) => {
+                println!("{}", e);
+                exit(0);
+            }
+        };
+        let constraint = match settings.constraint.as_deref().map(Constraint::new) {
+            Some(Ok(constraint)) if constraint.len() != alignment.len() => {
+                println!("The constraint does not match the length of the alignment");
+                exit(0);
+            }
+            Some(Ok(constraint)) => Some(constraint),
+            Some(Err(_)) => {
+                println!("The given constraint is invalid");
+                exit(0);
+            }
+            None => None,
+        };
+        let folded =
+            filled(alignment.fold(scoring.as_ref(), 1, constraint.as_ref(), settings.max_span));
+        let (structure, score) = match folded {
+            Some(folded) => folded,
+            None => {
+                println!("The constraint cannot be satisfied");
+                exit(0);
+            }
+        };
+
+        let width = alignment.names().iter().map(String::len).max().unwrap_or(0);
+        let width = width.max("#=GC SS_cons".len());
+        println!("# STOCKHOLM 1.0");
+        println!("#=GF CC Consensus score {:.2}", score);
+        println!();
+        for (name, row) in alignment.names().iter().zip(alignment.rows()) {
+            let row: String = (0..row.len()).map(|i| row[i]).collect();
+            println!("{:width$} {}", name, row, width = width);
+        }
+        println!("{:width$} {}", "#=GC SS_cons", structure, width = width);
+        println!("//");
+        exit(0);
+    }
+
+    if let Some(window) = settings.window {
+        let scanner = match WindowScanner::new(window, 1) {
+            Ok(scanner) => scanner.with_scoring(scoring),
+            Err(error) => {
+                println!("{}", error);
+                exit(0);
+            }
+        };
+        let result = if let Some(sequence) = settings.sequence {
+            scan(scanner, std::io::Cursor::new(sequence))
+        } else if let Some(path) = settings.file {
+            let file = File::open(path).expect("File could not be read");
+            scan(scanner, BufReader::new(file))
+        } else {
+            println!("Please call nussinov either with a sequence or a file path");
+            exit(0);
+        };
+        if result.is_err() {
+            println!("The given RNA-sequence is invalid");
+        }
+        exit(0);
+    }
+
+    // Only the default run folds with the energy model or with pseudoknots; every other mode uses
+    // the nested Nussinov recursion.
+    let modes = [
+        (settings.probabilities, "--probabilities"),
+        (settings.mea.is_some(), "--mea"),
+        (settings.centroid, "--centroid"),
+        (settings.count, "--count"),
+        (settings.grammar.is_some(), "--grammar"),
+        (settings.sample.is_some(), "--sample"),
+        (settings.random_optimal, "--random-optimal"),
+        (settings.suboptimal.is_some(), "--suboptimal"),
+        (settings.top.is_some(), "--top"),
+    ];
+    let algorithm = match settings.algorithm {
+        Algorithm::Nussinov => None,
+        Algorithm::Zuker => Some("Zuker"),
+        Algorithm::Pseudoknot => Some("Pseudoknot"),
+    };
+    if let Some(algorithm) = algorithm {
+        if let Some((_, mode)) = modes.iter().find(|(selected, _)| *selected) {
+            println!("{} folding does not support {}", algorithm, mode);
+            exit(0);
+        }
+    }
+
+    let sequence = if let Some(sequence) = settings.sequence {
+        sequence
+    } else if let Some(path) = settings.file {
+        std::fs::read_to_string(path).expect("File could not be read")
     } else {
         println!("Please call nussinov either with a sequence or a file path");
         exit(0);
+    };
+
+    let nussinov = Nussinov::new(&sequence, 1)
+        .with_scoring(scoring)
+        .with_algorithm(settings.algorithm)
+        .with_max_span(settings.max_span)
+        .with_ambiguous(settings.ambiguous)
+        .with_max_structures(settings.max_structures);
+    let mut nussinov = match nussinov.with_circular(settings.circular) {
+        Ok(nussinov) => nussinov,
+        Err(e) => {
+            println!("{}", e);
+            exit(0);
+        }
+    };
+
+    if let Some(constraint) = settings.constraint {
+        match Constraint::new(&constraint) {
+            Ok(constraint) => match nussinov.with_constraint(constraint) {
+                Ok(constrained) => nussinov = constrained,
+                Err(e) => {
+                    println!("{}", e);
+                    exit(0);
+                }
+            },
+            Err(_) => {
+                println!("The given constraint is invalid");
+                exit(0);
+            }
+        }
+    }
+
+    if let Some(path) = settings.shape {
+        match ShapeData::from_file(&path) {
+            Ok(shape) => {
+                let shape = shape.with_parameters(settings.shape_slope, settings.shape_intercept);
+                match nussinov.with_shape(shape) {
+                    Ok(shaped) => nussinov = shaped,
+                    Err(e) => {
+                        println!("{}", e);
+                        exit(0);
+                    }
+                }
+            }
+            Err(e) => {
+                println!("{}", e);
+                exit(0);
+            }
+        }
+    }
+
+    if let Some(Command::Eval(eval)) = &settings.command {
+        let max_structures = settings.max_structures.unwrap_or(usize::MAX);
+        let structures: Vec<String> = filled(nussinov.structures()).take(max_structures).collect();
+        compare(&structures, &eval.reference, eval.slip);
+        exit(0);
+    }
+
+    if settings.probabilities {
+        let partition_function = filled(nussinov.partition_function());
+        if partition_function.ensemble_free_energy().is_infinite() {
+            println!("The constraint cannot be satisfied");
+            exit(0);
+        }
+        println!(
+            "Ensemble free energy: {:.2} kcal/mol",
+            partition_function.ensemble_free_energy()
+        );
+        println!();
+        for row in partition_function.pair_probability_rows() {
+            let row: Vec<String> = row.iter().map(|p| format!("{:.3}", p)).collect();
+            println!("{}", row.join(" "));
+        }
+        exit(0);
+    }
+
+    if let Some(gamma) = settings.mea {
+        match filled(nussinov.mea(gamma)) {
+            Some(structure) => println!("{}", structure),
+            None => println!("The constraint cannot be satisfied"),
+        }
+        exit(0);
+    }
+
+    if settings.centroid {
+        match filled(nussinov.centroid()) {
+            Some(structure) => println!("{}", structure),
+            None => println!("The constraint cannot be satisfied"),
+        }
+        exit(0);
+    }
+
+    if settings.count {
+        let (optimal, total) = filled(nussinov.count());
+        println!("Optimal structures: {}", optimal);
+        println!("Secondary structures: {}", total);
+        exit(0);
+    }
+
+    if let Some(grammar) = settings.grammar {
+        let rules = std::fs::read_to_string(&grammar).unwrap_or(grammar);
+        let results = Grammar::parse(&rules)
+            .and_then(|grammar| evaluate_grammar(&nussinov, &grammar, &settings.algebra));
+        match results {
+            Ok(results) => results.iter().for_each(|result| println!("{}", result)),
+            Err(message) => println!("{}", message),
+        }
+        exit(0);
+    }
+
+    if let Some(n) = settings.sample {
+        let seed = settings.seed.unwrap_or_else(rand::random);
+        match filled(nussinov.sample(n, seed)) {
+            Some(structures) => structures.iter().for_each(|s| println!("{}", s)),
+            None => println!("The constraint cannot be satisfied"),
+        }
+        exit(0);
+    }
+
+    if settings.random_optimal {
+        let seed = settings.seed.unwrap_or_else(rand::random);
+        match filled(nussinov.random_optimal(seed)) {
+            Some(structure) => println!("{}", structure),
+            None => println!("The constraint cannot be satisfied"),
+        }
+        exit(0);
+    }
+
+    if let Some(delta) = settings.suboptimal {
+        for (structure, score) in filled(nussinov.suboptimal(delta, settings.limit)) {
+            println!("{} {}", structure, score);
+        }
+        exit(0);
+    }
+
+    if let Some(k) = settings.top {
+        match filled(nussinov.top(k)) {
+            Some(structures) => {
+                for (structure, score) in structures {
+                    println!("{} {}", structure, score);
+                }
+            }
+            None => println!("The constraint cannot be satisfied"),
+        }
+        exit(0);
     }
 
-    let mut nussinov = Nussinov::new(&sequence, 1);
     nussinov.run();
 }
+
+// Parameter files written by `train` start with their own header; every other file is read as a
+// scoring table.
+fn load_scoring(path: &str) -> Result<Box<dyn PairScoring>, String> {
+    let content = std::fs::read_to_string(path).map_err(|e| e.to_string())?;
+    if GrammarParameters::is_parameter_file(&content) {
+        return Ok(Box::new(GrammarParameters::parse(&content)?.scoring()));
+    }
+    Ok(Box::new(TableScoring::from_file(path)?))
+}
+
+// Streams the input line by line so that chromosome-sized files never have to be held in memory.
+// FASTA headers and comments are skipped.
+fn scan<R: BufRead>(mut scanner: WindowScanner, reader: R) -> Result<(), InvalidSequence> {
+    for line in reader.lines() {
+        let line = line.expect("File could not be read");
+        if line.starts_with('>') || line.starts_with(';') {
+            continue;
+        }
+        for nucleotide in line.chars().filter(|c| !c.is_whitespace()) {
+            scanner.push(nucleotide)?.iter().for_each(print_local);
+        }
+    }
+    scanner.finish().iter().for_each(print_local);
+    Ok(())
+}
+
+// The result of a method filling a matrix; exits if a score overflowed it.
+fn filled<T, E: Display>(result: Result<T, E>) -> T {
+    result.unwrap_or_else(|e| {
+        println!("{}", e);
+        exit(0);
+    })
+}
+
+fn print_local(local: &LocalStructure) {
+    println!(
+        "{} {} {} {}",
+        local.structure, local.score, local.start, local.end
+    );
+}
+
+fn compare(predicted: &[String], reference: &str, slip: bool) {
+    if Comparison::new(reference, reference, slip).is_err() {
+        println!("The given reference structure is invalid");
+        return;
+    }
+
+    println!("structure\tdistance\tsensitivity\tppv\tf1\tmcc");
+    for structure in predicted {
+        match Comparison::new(structure, reference, slip) {
+            Ok(c) => println!(
+                "{}\t{}\t{:.3}\t{:.3}\t{:.3}\t{:.3}",
+                structure, c.distance, c.sensitivity, c.ppv, c.f1, c.mcc
+            ),
+            Err(_) => {
+                println!(
+                    "{} cannot be compared with the reference structure",
+                    structure
+                );
+            }
+        }
+    }
+}
+
+// Evaluates the grammar with an algebra named like `score`, or a product of two like `score*count`.
+fn evaluate_grammar(
+    nussinov: &Nussinov,
+    grammar: &Grammar,
+    algebra: &str,
+) -> Result<Vec<String>, String> {
+    let mut names = algebra.split('*').map(str::trim);
+    let (first, second) = (names.next().unwrap_or_default(), names.next());
+    if names.next().is_some() {
+        return Err("Only products of two algebras are supported".into());
+    }
+
+    match first {
+        "score" => evaluate_product(nussinov, grammar, Score, second),
+        "count" => evaluate_product(nussinov, grammar, Count, second),
+        "structure" => evaluate_product(nussinov, grammar, DotBracket, second),
+        "shape" => evaluate_product(nussinov, grammar, Shape, second),
+        _ => Err(format!("Unknown algebra {}", first)),
+    }
+}
+
+fn evaluate_product<A: Algebra>(
+    nussinov: &Nussinov,
+    grammar: &Grammar,
+    first: A,
+    second: Option<&str>,
+) -> Result<Vec<String>, String> {
+    if second.is_some() && !first.is_selective() {
+        return Err("The first algebra of a product has to choose among its candidates".into());
+    }
+    match second {
+        None => Ok(show(nussinov, grammar, first)),
+        Some("score") => Ok(show(nussinov, grammar, Product(first, Score))),
+        Some("count") => Ok(show(nussinov, grammar, Product(first, Count))),
+        Some("structure") => Ok(show(nussinov, grammar, Product(first, DotBracket))),
+        Some("shape") => Ok(show(nussinov, grammar, Product(first, Shape))),
+        Some(name) => Err(format!("Unknown algebra {}", name)),
+    }
+}
+
+fn show<A: Algebra>(nussinov: &Nussinov, grammar: &Grammar, algebra: A) -> Vec<String> {
+    let values = nussinov.evaluate_grammar(grammar, &algebra);
+    values.iter().map(|value| algebra.show(value)).collect()
+}
@@ -1,157 +1,332 @@
-use crate::matrix_builder::MatrixBuilder;
-use crate::matrix_builder::UnpairedType;
-use array2d::Array2D;
-use std::fmt::Debug;
-use std::fmt::Display;
-use std::ops::Index;
-use std::ops::IndexMut;
-
-pub struct Matrix(Array2D<MatrixNode>);
-
-#[derive(Clone, Debug)]
-pub struct MatrixNode {
-    pub value: usize,
-    pub position: Position,
-    pub trace: Trace,
-}
-
-pub type Trace = Vec<TraceType>;
-
-#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
-pub struct Position {
-    pub i: usize,
-    pub j: usize,
-}
-
-#[derive(Clone, Debug)]
-pub enum TraceType {
-    Complementary(Position),
-    Unpaired(Position),
-    Decomposition(Position, Position),
-}
-
-impl Display for Matrix {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        let mut s = String::new();
-
-        for i in 0..self.rows() {
-            for j in 0..self.columns() {
-                let node = &self[Position::from(i, j)];
-                let number_string = format!("{} ", node.value);
-                s.push_str(&number_string);
-            }
-            s.push('\n');
-        }
-        writeln!(f, "{}", s).unwrap();
-
-        Ok(())
-    }
-}
-
-impl Matrix {
-    pub fn new(size: usize) -> Matrix {
-        let mut counter = 0;
-        let generator = || {
-            let j = counter % (size + 1);
-            let i = (counter - j) / (size + 1);
-            counter += 1;
-
-            MatrixNode {
-                value: 0,
-                position: Position::from(i, j),
-                trace: Trace::new(),
-            }
-        };
-        let array = Array2D::filled_by_row_major(generator, size + 1, size + 1);
-
-        Matrix(array)
-    }
-
-    pub fn columns(&self) -> usize {
-        self.0.row_len()
-    }
-
-    pub fn rows(&self) -> usize {
-        self.0.column_len()
-    }
-
-    pub fn fill(&mut self, builder: &dyn MatrixBuilder) {
-        builder.fill(self);
-    }
-
-    pub fn root(&self) -> &MatrixNode {
-        &self[Position::from(0, self.columns() - 1)]
-    }
-}
-
-impl Index<Position> for Matrix {
-    type Output = MatrixNode;
-
-    fn index(&self, index: Position) -> &Self::Output {
-        &self.0[index.into()]
-    }
-}
-
-impl IndexMut<Position> for Matrix {
-    fn index_mut(&mut self, index: Position) -> &mut Self::Output {
-        &mut self.0[index.into()]
-    }
-}
-
-impl Position {
-    pub fn from(i: usize, j: usize) -> Position {
-        Position { i, j }
-    }
-
-    pub fn is_diagonal_relation(&self, pos: &Position) -> bool {
-        (self.i + 1) == pos.i && self.j == (pos.j + 1)
-    }
-
-    pub fn is_diagonal(&self) -> bool {
-        self.i == self.j
-    }
-
-    pub fn get_unpaired(&self, unpaired_type: UnpairedType) -> Position {
-        match unpaired_type {
-            UnpairedType::Left => Position {
-                i: self.i,
-                j: self.j - 1,
-            },
-            UnpairedType::Bottom => Position {
-                i: self.i + 1,
-                j: self.j,
-            },
-        }
-    }
-
-    pub fn get_decomposition(&self, k: usize) -> (Position, Position) {
-        let pos1 = Position { i: self.i, j: k };
-        let pos2 = Position { i: k, j: self.j };
-        (pos1, pos2)
-    }
-
-    pub fn get_complementary(&self) -> Position {
-        Position {
-            i: self.i + 1,
-            j: self.j - 1,
-        }
-    }
-}
-
-impl From<&Position> for (usize, usize) {
-    fn from(pos: &Position) -> Self {
-        (pos.i, pos.j)
-    }
-}
-
-impl From<Position> for (usize, usize) {
-    fn from(pos: Position) -> Self {
-        (pos.i, pos.j)
-    }
-}
-
-impl Display for Position {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "({},{})", self.i, self.j)
-    }
-}
+use crate::matrix_builder::MatrixBuilder;
+use crate::matrix_builder::UnpairedType;
+use std::fmt::Debug;
+use std::fmt::Display;
+use std::ops::Index;
+use std::ops::IndexMut;
+
+// Only the upper triangle of the (n + 1) × (n + 1) cells is stored, row by row, and only the
+// score of every cell; with a band only the cells a builder restricted to it fills, see
+// `Triangle::banded`. The traces of a cell are recomputed from the cells it derives by the
+// `MatrixBuilder` that filled the matrix, see `MatrixBuilder::traces`.
+pub struct Matrix {
+    nodes: Triangle<MatrixNode>,
+}
+
+// The cells (i, j) with i <= j of a square table, stored row by row. Below the diagonal there is
+// nothing to store.
+#[derive(Clone, Debug)]
+pub struct Triangle<T> {
+    size: usize,
+    width: usize,
+    // Offset of the first cell of every row.
+    starts: Vec<usize>,
+    values: Vec<T>,
+}
+
+// Score of a cell in 32 bits, at most `MatrixNode::MAX`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct MatrixNode(u32);
+
+// A score too large for a `MatrixNode`. The matrix cannot be filled then, since every cell
+// deriving the cell would be scored wrongly.
+#[derive(Debug)]
+pub struct ScoreOverflow;
+
+pub type Trace = Vec<TraceType>;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Position {
+    pub i: usize,
+    pub j: usize,
+}
+
+#[derive(Clone, Debug)]
+pub enum TraceType {
+    Complementary(Position),
+    Unpaired(Position),
+    Decomposition(Position, Position),
+    // The prefix cell followed by a pair closing the inner cell; nucleotides after the pair stay
+    // unpaired. Every structure has exactly one derivation built from these and `Unpaired(Left)`.
+    Closed(Position, Position),
+    // The prefix cell followed by the H-type pseudoknot spanning the second cell, whose stems are
+    // kept by the `PseudoknotMatrixBuilder` that filled the matrix.
+    Pseudoknot(Position, Position),
+    // Pair closing the given cell with every nucleotide outside of it unpaired (empty cells close
+    // no pair). Only used for the root of circular molecules.
+    Exterior(Position),
+}
+
+impl Display for Matrix {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut s = String::new();
+
+        for i in 0..self.rows() {
+            for j in 0..self.columns() {
+                let node = &self[Position::from(i, j)];
+                let number_string = format!("{} ", node.value());
+                s.push_str(&number_string);
+            }
+            s.push('\n');
+        }
+        writeln!(f, "{}", s).unwrap();
+
+        Ok(())
+    }
+}
+
+impl Matrix {
+    pub fn new(size: usize) -> Matrix {
+        Matrix::banded(size, size)
+    }
+
+    // Only stores the cells with `j - i <= width` and the first row, which holds the root. The
+    // others read as infeasible.
+    pub fn banded(size: usize, width: usize) -> Matrix {
+        let size = size + 1;
+        let mut matrix = Matrix {
+            nodes: Triangle::banded(MatrixNode::INFEASIBLE, size, width),
+        };
+        for i in 0..size {
+            matrix[Position::from(i, i)] = MatrixNode(0);
+        }
+
+        matrix
+    }
+
+    pub fn width(&self) -> usize {
+        self.nodes.width()
+    }
+
+    pub fn columns(&self) -> usize {
+        self.nodes.size()
+    }
+
+    pub fn rows(&self) -> usize {
+        self.nodes.size()
+    }
+
+    pub fn fill(&mut self, builder: &dyn MatrixBuilder) -> Result<(), ScoreOverflow> {
+        builder.fill(self)
+    }
+
+    pub fn root(&self) -> &MatrixNode {
+        &self[self.root_position()]
+    }
+
+    pub fn root_position(&self) -> Position {
+        Position::from(0, self.columns() - 1)
+    }
+}
+
+impl<T: Clone> Triangle<T> {
+    // A `size` × `size` table.
+    pub fn filled_with(value: T, size: usize) -> Triangle<T> {
+        Triangle::banded(value, size, size)
+    }
+
+    // Only the cells with `j - i <= width` and the whole first row, which is all that a recursion
+    // restricted to the band needs to reach the root (0, size - 1).
+    pub fn banded(value: T, size: usize, width: usize) -> Triangle<T> {
+        let mut starts = Vec::with_capacity(size);
+        let mut len = 0;
+        for i in 0..size {
+            starts.push(len);
+            len += match i {
+                0 => size,
+                _ => width.min(size - 1 - i) + 1,
+            };
+        }
+
+        Triangle {
+            size,
+            width,
+            starts,
+            values: vec![value; len],
+        }
+    }
+}
+
+impl<T> Triangle<T> {
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    // `None` below the diagonal and outside of the band.
+    pub fn get(&self, (i, j): (usize, usize)) -> Option<&T> {
+        self.offset(i, j).map(|offset| &self.values[offset])
+    }
+
+    // Offset of a cell in the rows of the triangle; `None` if it is not stored.
+    fn offset(&self, i: usize, j: usize) -> Option<usize> {
+        debug_assert!(j < self.size, "({},{}) lies outside of the table", i, j);
+        (i <= j && (i == 0 || j - i <= self.width)).then(|| self.starts[i] + (j - i))
+    }
+}
+
+impl<T> Index<(usize, usize)> for Triangle<T> {
+    type Output = T;
+
+    fn index(&self, (i, j): (usize, usize)) -> &Self::Output {
+        self.get((i, j))
+            .unwrap_or_else(|| panic!("({},{}) is not stored", i, j))
+    }
+}
+
+impl<T> IndexMut<(usize, usize)> for Triangle<T> {
+    fn index_mut(&mut self, (i, j): (usize, usize)) -> &mut Self::Output {
+        let offset = self
+            .offset(i, j)
+            .unwrap_or_else(|| panic!("({},{}) is not stored", i, j));
+        &mut self.values[offset]
+    }
+}
+
+impl MatrixNode {
+    pub const INFEASIBLE: MatrixNode = MatrixNode(u32::MAX);
+    pub const MAX: MatrixNode = MatrixNode(u32::MAX - 1);
+
+    // Cells without any trace admit no valid structure.
+    pub fn new(trace: &Trace, value: usize) -> Result<MatrixNode, ScoreOverflow> {
+        MatrixNode::from_value((!trace.is_empty()).then_some(value))
+    }
+
+    // `None` for cells that admit no valid structure.
+    pub fn from_value(value: Option<usize>) -> Result<MatrixNode, ScoreOverflow> {
+        match value {
+            Some(value) => u32::try_from(value)
+                .ok()
+                .filter(|value| *value <= MatrixNode::MAX.0)
+                .map(MatrixNode)
+                .ok_or(ScoreOverflow),
+            None => Ok(MatrixNode::INFEASIBLE),
+        }
+    }
+
+    // Infeasible cells have the value 0.
+    pub fn value(&self) -> usize {
+        match self.is_feasible() {
+            true => self.0 as usize,
+            false => 0,
+        }
+    }
+
+    pub fn is_feasible(&self) -> bool {
+        *self != MatrixNode::INFEASIBLE
+    }
+}
+
+// Cells below the diagonal are infeasible.
+impl Index<Position> for Matrix {
+    type Output = MatrixNode;
+
+    fn index(&self, index: Position) -> &Self::Output {
+        self.nodes
+            .get(index.into())
+            .unwrap_or(&MatrixNode::INFEASIBLE)
+    }
+}
+
+impl IndexMut<Position> for Matrix {
+    fn index_mut(&mut self, index: Position) -> &mut Self::Output {
+        &mut self.nodes[index.into()]
+    }
+}
+
+impl Position {
+    pub fn from(i: usize, j: usize) -> Position {
+        Position { i, j }
+    }
+
+    pub fn is_diagonal_relation(&self, pos: &Position) -> bool {
+        (self.i + 1) == pos.i && self.j == (pos.j + 1)
+    }
+
+    pub fn is_diagonal(&self) -> bool {
+        self.i == self.j
+    }
+
+    pub fn get_unpaired(&self, unpaired_type: UnpairedType) -> Position {
+        match unpaired_type {
+            UnpairedType::Left => Position {
+                i: self.i,
+                j: self.j - 1,
+            },
+            UnpairedType::Bottom => Position {
+                i: self.i + 1,
+                j: self.j,
+            },
+        }
+    }
+
+    pub fn get_decomposition(&self, k: usize) -> (Position, Position) {
+        let pos1 = Position { i: self.i, j: k };
+        let pos2 = Position { i: k, j: self.j };
+        (pos1, pos2)
+    }
+
+    pub fn get_complementary(&self) -> Position {
+        Position {
+            i: self.i + 1,
+            j: self.j - 1,
+        }
+    }
+}
+
+impl From<&Position> for (usize, usize) {
+    fn from(pos: &Position) -> Self {
+        (pos.i, pos.j)
+    }
+}
+
+impl From<Position> for (usize, usize) {
+    fn from(pos: Position) -> Self {
+        (pos.i, pos.j)
+    }
+}
+
+impl Display for Position {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "({},{})", self.i, self.j)
+    }
+}
+
+impl Display for ScoreOverflow {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "The scores exceed the largest score a matrix can hold")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{MatrixNode, Triangle};
+
+    #[test]
+    fn scores_beyond_32_bits_overflow() {
+        assert_eq!(MatrixNode::from_value(Some(7)).unwrap().value(), 7);
+        let max = MatrixNode::MAX.value();
+        assert_eq!(MatrixNode::from_value(Some(max)).unwrap(), MatrixNode::MAX);
+        assert!(MatrixNode::from_value(Some(max + 1)).is_err());
+        assert!(MatrixNode::from_value(Some(usize::MAX)).is_err());
+        assert!(MatrixNode::MAX.is_feasible());
+        assert!(!MatrixNode::from_value(None).unwrap().is_feasible());
+    }
+
+    #[test]
+    fn triangle_cells_do_not_overlap() {
+        let size = 5;
+        let mut triangle = Triangle::filled_with(None, size);
+        for i in 0..size {
+            for j in i..size {
+                assert_eq!(triangle[(i, j)], None);
+                triangle[(i, j)] = Some((i, j));
+            }
+        }
+        assert_eq!(triangle.get((3, 1)), None);
+        assert!((0..size).all(|i| (i..size).all(|j| triangle[(i, j)] == Some((i, j)))));
+    }
+}
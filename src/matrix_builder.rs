@@ -1,16 +1,55 @@
+use std::{
+    borrow::Cow,
+    cell::{OnceCell, Ref, RefCell},
+    collections::HashMap,
+};
+
+use rand::Rng;
+
 use crate::{
-    matrix::{Matrix, MatrixNode, Position, Trace, TraceType},
+    constraint::Constraint,
+    energy_parameters::{self, INF},
+    matrix::{Matrix, MatrixNode, Position, ScoreOverflow, Trace, TraceType, Triangle},
     nussinov::RNASequence,
+    pair_scoring::{PairScoring, UnitScoring},
+    semiring::{MaxPlus, Semiring, SumProduct},
+    shape::ShapeData,
+    structure::Structure,
 };
 
+// The partition function exceeds the Boltzmann weight of the optimal structure by a factor growing
+// exponentially with the length. On random sequences it grows by about 1.45 to 1.5 per nucleotide
+// with unit scoring and by 1.1 to 1.2 with weighted scoring. Dividing every nucleotide by this
+// estimate on top of the optimum leaves a scaled partition function of about (growth / 1.4)^n,
+// which stays within the range of f64 (e^±709) for sequences of several thousand nucleotides.
+const ENSEMBLE_GROWTH: f64 = 1.4;
+
+// Cached pair scores take 32 bits. Nucleotides that cannot pair are stored as `NO_PAIR`; scores
+// too large to store are stored as `LARGE_SCORE` and recomputed whenever they are asked for.
+const NO_PAIR: u32 = u32::MAX;
+const LARGE_SCORE: u32 = u32::MAX - 1;
+
 pub trait MatrixBuilder {
-    fn fill<'m>(&self, matrix: &'m mut Matrix);
+    fn fill(&self, matrix: &mut Matrix) -> Result<(), ScoreOverflow>;
+
+    // The co-optimal traces of a cell of a matrix filled by this builder, recomputed from the
+    // cells they derive. Diagonal cells have none.
+    fn traces(&self, matrix: &Matrix, position: Position) -> Trace;
 }
 
-#[derive(Debug)]
 pub struct NussinovMatrixBuilder<'s> {
     sequence: &'s RNASequence,
     minimal_loop_length: usize,
+    scoring: &'s dyn PairScoring,
+    constraint: Option<&'s Constraint>,
+    shape: Option<&'s ShapeData>,
+    circular: bool,
+    max_span: Option<usize>,
+    ambiguous: bool,
+    // `pair_score` of every partner of each j within the maximal span, computed on first use
+    // since a fill asks for each O(n) times. A column is only computed once a pair ending at j is
+    // asked for. See `NO_PAIR` and `LARGE_SCORE` for how the scores are stored.
+    pair_scores: Vec<OnceCell<Vec<u32>>>,
 }
 
 struct DiagonalMatrixIterator {
@@ -18,6 +57,16 @@ struct DiagonalMatrixIterator {
     rows: usize,
     position: usize,
     gap: usize,
+    max_gap: usize,
+}
+
+// The tables of pairs (k, l) only hold those within the maximal span of the builder.
+pub struct PartitionFunction {
+    free_energy: f64,
+    probabilities: Triangle<f64>,
+    inside: Triangle<f64>,
+    closed: Triangle<f64>,
+    unpaired: Vec<f64>,
 }
 
 pub enum UnpairedType {
@@ -25,19 +74,166 @@ pub enum UnpairedType {
     Bottom,
 }
 
-impl NussinovMatrixBuilder<'_> {
-    pub fn new(sequence: &RNASequence, minimal_loop_length: usize) -> NussinovMatrixBuilder {
+impl<'s> NussinovMatrixBuilder<'s> {
+    pub fn new(sequence: &'s RNASequence, minimal_loop_length: usize) -> NussinovMatrixBuilder<'s> {
         NussinovMatrixBuilder {
             sequence,
             minimal_loop_length,
+            scoring: &UnitScoring,
+            constraint: None,
+            shape: None,
+            circular: false,
+            max_span: None,
+            ambiguous: false,
+            pair_scores: (0..sequence.len()).map(|_| OnceCell::new()).collect(),
         }
     }
 
+    pub fn with_scoring(mut self, scoring: &'s dyn PairScoring) -> NussinovMatrixBuilder<'s> {
+        self.scoring = scoring;
+        self.clear_pair_scores();
+        self
+    }
+
+    pub fn with_constraint(mut self, constraint: &'s Constraint) -> NussinovMatrixBuilder<'s> {
+        self.constraint = Some(constraint);
+        self.clear_pair_scores();
+        self
+    }
+
+    pub fn with_shape(mut self, shape: &'s ShapeData) -> NussinovMatrixBuilder<'s> {
+        self.shape = Some(shape);
+        self.clear_pair_scores();
+        self
+    }
+
+    pub fn with_circular(mut self, circular: bool) -> NussinovMatrixBuilder<'s> {
+        self.circular = circular;
+        self
+    }
+
+    // Never pairs `i` with `j` when `j - i > max_span`.
+    pub fn with_max_span(mut self, max_span: Option<usize>) -> NussinovMatrixBuilder<'s> {
+        self.max_span = max_span;
+        self.clear_pair_scores();
+        self
+    }
+
+    // The textbook recursion, which also pairs the first nucleotide, leaves it unpaired and splits
+    // the cell anywhere, derives most structures several times. It is kept for teaching; by default
+    // every cell either leaves its last nucleotide unpaired or pairs it with exactly one partner.
+    pub fn with_ambiguous(mut self, ambiguous: bool) -> NussinovMatrixBuilder<'s> {
+        self.ambiguous = ambiguous;
+        self
+    }
+
+    pub fn minimal_loop_length(&self) -> usize {
+        self.minimal_loop_length
+    }
+
+    fn clear_pair_scores(&mut self) {
+        self.pair_scores.iter_mut().for_each(|pair_scores| {
+            pair_scores.take();
+        });
+    }
+
+    // An empty matrix holding the cells this builder fills: only the band with `--max-span`.
+    pub fn new_matrix(&self) -> Matrix {
+        Matrix::banded(self.sequence.len(), self.max_gap())
+    }
+
+    // The unambiguous recursion shared by the fill and every semiring evaluation: the last
+    // nucleotide of the cell is either unpaired or pairs with some `k`. Every rule comes with the
+    // score of the pair it adds.
+    pub fn rules(&self, pos: Position) -> impl Iterator<Item = (TraceType, usize)> + '_ {
+        let j = pos.j - 1;
+        let unpaired = self
+            .can_be_unpaired(j)
+            .then(|| (TraceType::Unpaired(Position::from(pos.i, j)), 0));
+        let partners = self.partners(j);
+        let closed = (partners.start.max(pos.i)..j).filter_map(move |k| {
+            let score = self.pair_score(k, j)?;
+            let trace_type = TraceType::Closed(Position::from(pos.i, k), Position::from(k + 1, j));
+            Some((trace_type, score))
+        });
+
+        unpaired.into_iter().chain(closed)
+    }
+
+    // Evaluates the recursion of `rules` over a semiring for every cell of the linear sequence
+    // that the fill computes, see `cells`.
+    pub fn inside<S: Semiring>(&self, semiring: &S) -> Triangle<S::Value> {
+        let n = self.sequence.len();
+        let mut inside = Triangle::banded(semiring.zero(), n + 1, self.max_gap());
+        // Pair values may be expensive, like Boltzmann weights, but only depend on the score.
+        let mut pair_values = HashMap::new();
+        for i in 0..=n {
+            inside[(i, i)] = semiring.one();
+        }
+
+        for position in self.cells() {
+            let value = self.evaluate_rules(
+                semiring,
+                position,
+                |pos| Cow::Borrowed(&inside[pos.into()]),
+                |score| {
+                    pair_values
+                        .entry(score)
+                        .or_insert_with(|| semiring.pair(score))
+                        .clone()
+                },
+            );
+            inside[position.into()] = value;
+        }
+
+        inside
+    }
+
+    // Sums the rules of a cell over a semiring, given the values of the cells they derive and of
+    // the pairs they add.
+    fn evaluate_rules<'v, S: Semiring>(
+        &self,
+        semiring: &S,
+        position: Position,
+        value: impl Fn(Position) -> Cow<'v, S::Value>,
+        mut pair: impl FnMut(usize) -> S::Value,
+    ) -> S::Value
+    where
+        S::Value: 'v,
+    {
+        let unpaired = semiring.unpaired();
+        self.rules(position)
+            .fold(semiring.zero(), |sum, (trace_type, score)| {
+                let derived = derive(semiring, &trace_type, &unpaired, || pair(score), &value);
+                semiring.add(&sum, &derived)
+            })
+    }
+
+    // Value of a rule in the filled part of `matrix`, `None` if a cell it derives is infeasible.
+    fn rule_value(&self, matrix: &Matrix, trace_type: &TraceType, score: usize) -> Option<usize> {
+        let value = |pos: Position| Cow::Owned(max_plus(matrix, pos));
+        derive(
+            &MaxPlus,
+            trace_type,
+            &MaxPlus.unpaired(),
+            || MaxPlus.pair(score),
+            &value,
+        )
+    }
+
     fn determine_max(&self, matrix: &Matrix, pos: &Position) -> (Trace, usize) {
+        if !self.ambiguous {
+            let possible_traces = self.rules(*pos).filter_map(|(trace_type, score)| {
+                let value = self.rule_value(matrix, &trace_type, score)?;
+                Some((trace_type, value))
+            });
+            return select_max(possible_traces);
+        }
+
         let mut possible_traces = vec![
             self.get_complementary(matrix, pos),
-            NussinovMatrixBuilder::get_unpaired(matrix, pos, UnpairedType::Left),
-            NussinovMatrixBuilder::get_unpaired(matrix, pos, UnpairedType::Bottom),
+            self.get_unpaired(matrix, pos, UnpairedType::Left),
+            self.get_unpaired(matrix, pos, UnpairedType::Bottom),
         ];
         if let Some(mut t) = self.get_decomposition(matrix, pos) {
             possible_traces.append(&mut t);
@@ -45,32 +241,196 @@ impl NussinovMatrixBuilder<'_> {
 
         let mut max = 0;
 
-        for pt in &possible_traces {
-            if let Some(x) = pt {
-                let (_, value) = x;
-                if max < *value {
-                    max = *value;
-                }
+        for (_, value) in possible_traces.iter().flatten() {
+            if max < *value {
+                max = *value;
             }
         }
 
         let (trace, _): (Vec<TraceType>, Vec<usize>) = possible_traces
             .into_iter()
-            .filter_map(|x| x)
+            .flatten()
             .filter(|(_, v)| *v == max)
             .unzip();
 
         (trace, max)
     }
 
+    // Score of pairing the nucleotides `i` and `j` (both inclusive), if they may pair at all.
+    pub fn pair_score(&self, i: usize, j: usize) -> Option<usize> {
+        if i >= j {
+            return None;
+        }
+        let partners = self.partners(j);
+        if i < partners.start {
+            return None;
+        }
+        let pair_scores = self.pair_scores[j].get_or_init(|| {
+            partners
+                .clone()
+                .map(|k| match self.compute_pair_score(k, j) {
+                    Some(score) => u32::try_from(score)
+                        .ok()
+                        .filter(|score| *score < LARGE_SCORE)
+                        .unwrap_or(LARGE_SCORE),
+                    None => NO_PAIR,
+                })
+                .collect()
+        });
+        match pair_scores[i - partners.start] {
+            NO_PAIR => None,
+            LARGE_SCORE => self.compute_pair_score(i, j),
+            score => Some(score as usize),
+        }
+    }
+
+    fn compute_pair_score(&self, i: usize, j: usize) -> Option<usize> {
+        let hairpin_too_short = (self.minimal_loop_length + i) >= j;
+        if hairpin_too_short && !self.sequence.is_intermolecular(i, j) {
+            return None;
+        }
+        if self.max_span.is_some_and(|max_span| j - i > max_span) {
+            return None;
+        }
+        if let Some(constraint) = self.constraint {
+            if !constraint.can_pair(i, j) {
+                return None;
+            }
+        }
+        let score = self.scoring.score_at(self.sequence, i, j)?;
+        match self.shape {
+            Some(shape) => shape.adjust(score, i, j),
+            None => Some(score),
+        }
+    }
+
+    // Pair scores are multiples of this, see `ShapeData::SCALE`.
+    pub fn score_scale(&self) -> usize {
+        self.shape.map_or(1, |_| ShapeData::SCALE)
+    }
+
+    pub fn can_be_unpaired(&self, i: usize) -> bool {
+        match self.constraint {
+            Some(constraint) => constraint.can_be_unpaired(i),
+            None => true,
+        }
+    }
+
+    // `kt` is given in whole score units; the free energy is returned in them, too.
+    pub fn partition_function(&self, matrix: &Matrix, kt: f64) -> PartitionFunction {
+        let n = self.sequence.len();
+        let scaled_kt = kt * self.score_scale() as f64;
+        let scale = if n > 0 {
+            (matrix.root().value() as f64 / (n as f64 * scaled_kt)).exp() * ENSEMBLE_GROWTH
+        } else {
+            1.0
+        };
+        let sum_product = SumProduct {
+            kt: scaled_kt,
+            scale,
+        };
+        let unpaired: Vec<f64> = (0..n)
+            .map(|i| {
+                if self.can_be_unpaired(i) {
+                    sum_product.unpaired()
+                } else {
+                    0.0
+                }
+            })
+            .collect();
+        let weight =
+            |k: usize, l: usize| self.pair_score(k, l).map(|score| sum_product.pair(score));
+
+        // Cells are stored like in the matrix, pairs (k, l) only within the maximal span.
+        let (max_gap, max_span) = (self.max_gap(), self.max_span.unwrap_or(n));
+        let inside = self.inside(&sum_product);
+        let mut closed = Triangle::banded(0.0, n.max(1), max_span);
+        for l in 0..n {
+            for k in self.partners(l) {
+                if let Some(w) = weight(k, l) {
+                    closed[(k, l)] = w * inside[(k + 1, l)];
+                }
+            }
+        }
+
+        let mut outside = Triangle::banded(0.0, n + 1, max_gap);
+        let mut closed_outside = Triangle::banded(0.0, n.max(1), max_span);
+        outside[(0, n)] = 1.0;
+
+        for d in (1..=n).rev() {
+            let last_row = if d <= max_gap { n - d } else { 0 };
+            for i in 0..=last_row {
+                let j = i + d;
+                let o = outside[(i, j)];
+                if o == 0.0 {
+                    continue;
+                }
+                outside[(i, j - 1)] += o * unpaired[j - 1];
+                for k in self.partners(j - 1).filter(|k| *k >= i) {
+                    let c = closed[(k, j - 1)];
+                    if c > 0.0 {
+                        outside[(i, k)] += o * c;
+                        closed_outside[(k, j - 1)] += o * inside[(i, k)];
+                    }
+                }
+            }
+            if d - 1 <= max_span {
+                for k in 0..=(n - d) {
+                    let l = k + d - 1;
+                    if let Some(w) = weight(k, l) {
+                        outside[(k + 1, l)] += closed_outside[(k, l)] * w;
+                    }
+                }
+            }
+        }
+
+        let total = inside[(0, n)];
+        let mut probabilities = Triangle::banded(0.0, n.max(1), max_span);
+        if total > 0.0 {
+            for l in 0..n {
+                for k in self.partners(l) {
+                    probabilities[(k, l)] = closed[(k, l)] * closed_outside[(k, l)] / total;
+                }
+            }
+        }
+
+        PartitionFunction {
+            free_energy: -kt * (total.ln() + n as f64 * scale.ln()),
+            probabilities,
+            inside,
+            closed,
+            unpaired,
+        }
+    }
+
     fn get_complementary(&self, matrix: &Matrix, pos: &Position) -> Option<(TraceType, usize)> {
-        let is_complement = self.sequence.is_complement(pos).unwrap_or_default();
-        if is_complement && (self.minimal_loop_length + pos.i) < (pos.j - 1) {
-            let node = &matrix[pos.get_complementary()];
-            let trace_type = TraceType::Complementary(node.position);
-            return Some((trace_type, node.value + 1));
+        let score = self.pair_score(pos.i, pos.j - 1)?;
+
+        let inner = pos.get_complementary();
+        let node = &matrix[inner];
+        if !node.is_feasible() {
+            return None;
         }
-        None
+        let trace_type = TraceType::Complementary(inner);
+        Some((trace_type, node.value() + score))
+    }
+
+    // Appends the pair (`prefix.j`, `l`) to the prefix cell.
+    fn get_closed(
+        &self,
+        matrix: &Matrix,
+        prefix: Position,
+        l: usize,
+    ) -> Option<(TraceType, usize)> {
+        let score = self.pair_score(prefix.j, l)?;
+
+        let inner = Position::from(prefix.j + 1, l);
+        let (node1, node2) = (&matrix[prefix], &matrix[inner]);
+        if !node1.is_feasible() || !node2.is_feasible() {
+            return None;
+        }
+        let trace_type = TraceType::Closed(prefix, inner);
+        Some((trace_type, node1.value() + score + node2.value()))
     }
 
     fn get_decomposition(
@@ -83,23 +443,23 @@ impl NussinovMatrixBuilder<'_> {
         for k in (pos.i + 2)..(pos.j - 1) {
             let (pos1, pos2) = pos.get_decomposition(k);
             let (node1, node2) = (&matrix[pos1], &matrix[pos2]);
-            let value: usize = node1.value + node2.value;
+            if !node1.is_feasible() || !node2.is_feasible() {
+                continue;
+            }
+            let value: usize = node1.value() + node2.value();
 
             match value_max {
                 Some(v) => {
                     if value == v {
-                        nodes_max
-                            .as_mut()
-                            .unwrap()
-                            .push((node1.position, node2.position));
+                        nodes_max.as_mut().unwrap().push((pos1, pos2));
                     } else if value > v {
                         value_max = Some(value);
-                        nodes_max = Some(vec![(node1.position, node2.position)]);
+                        nodes_max = Some(vec![(pos1, pos2)]);
                     }
                 }
                 None => {
                     value_max = Some(value);
-                    nodes_max = Some(vec![(node1.position, node2.position)]);
+                    nodes_max = Some(vec![(pos1, pos2)]);
                 }
             }
         }
@@ -117,32 +477,414 @@ impl NussinovMatrixBuilder<'_> {
     }
 
     fn get_unpaired(
+        &self,
         matrix: &Matrix,
         pos: &Position,
         unpaired_type: UnpairedType,
     ) -> Option<(TraceType, usize)> {
-        let node = &matrix[pos.get_unpaired(unpaired_type)];
-        let trace_type = TraceType::Unpaired(node.position);
-        return Some((trace_type, node.value));
+        let unpaired_index = match unpaired_type {
+            UnpairedType::Left => pos.j - 1,
+            UnpairedType::Bottom => pos.i,
+        };
+        let unpaired = pos.get_unpaired(unpaired_type);
+        let node = &matrix[unpaired];
+        if !self.can_be_unpaired(unpaired_index) || !node.is_feasible() {
+            return None;
+        }
+        let trace_type = TraceType::Unpaired(unpaired);
+        Some((trace_type, node.value()))
+    }
+}
+
+impl PartitionFunction {
+    pub fn ensemble_free_energy(&self) -> f64 {
+        self.free_energy
+    }
+
+    // Probability of the pair (i, j) in either order, zero beyond the maximal span.
+    pub fn pair_probability(&self, i: usize, j: usize) -> f64 {
+        let (k, l) = (i.min(j), i.max(j));
+        self.probabilities.get((k, l)).copied().unwrap_or(0.0)
+    }
+
+    // The full base-pair probability matrix, one row at a time.
+    pub fn pair_probability_rows(&self) -> impl Iterator<Item = Vec<f64>> + '_ {
+        let n = self.unpaired.len();
+        (0..n).map(move |i| (0..n).map(|j| self.pair_probability(i, j)).collect())
+    }
+
+    // Structure maximising the expected accuracy sum of 2γ·p(i, j) over its pairs plus the
+    // probabilities of its unpaired nucleotides to stay unpaired. Larger γ favours more pairs.
+    // Nucleotides the constraint forces to pair are never left unpaired.
+    pub fn mea(&self, gamma: f64) -> Structure {
+        let n = self.unpaired.len();
+        let max_span = self.probabilities.width();
+        let p = |k: usize, l: usize| self.pair_probability(k, l);
+        let unpaired: Vec<f64> = (0..n)
+            .map(|i| match self.unpaired[i] > 0.0 {
+                true => {
+                    let partners = i.saturating_sub(max_span)..(i + max_span + 1).min(n);
+                    1.0 - partners.map(|j| p(i, j)).sum::<f64>()
+                }
+                false => f64::NEG_INFINITY,
+            })
+            .collect();
+
+        let mut accuracy = Triangle::banded(0.0, n + 1, max_span + 1);
+        for d in 1..=n {
+            let last_row = if d <= max_span + 1 { n - d } else { 0 };
+            for i in 0..=last_row {
+                let j = i + d;
+                let mut best = accuracy[(i, j - 1)] + unpaired[j - 1];
+                for k in self.partners(j - 1).filter(|k| *k >= i) {
+                    if p(k, j - 1) > 0.0 {
+                        let value =
+                            accuracy[(i, k)] + 2.0 * gamma * p(k, j - 1) + accuracy[(k + 1, j - 1)];
+                        best = best.max(value);
+                    }
+                }
+                accuracy[(i, j)] = best;
+            }
+        }
+
+        let mut structure = Structure::new(n);
+        let mut regions = vec![(0, n)];
+        while let Some((i, mut j)) = regions.pop() {
+            while j > i {
+                let value = accuracy[(i, j)];
+                if value == accuracy[(i, j - 1)] + unpaired[j - 1] && value.is_finite() {
+                    j -= 1;
+                    continue;
+                }
+                let k = self
+                    .partners(j - 1)
+                    .filter(|k| *k >= i)
+                    .find(|&k| {
+                        p(k, j - 1) > 0.0
+                            && value
+                                == accuracy[(i, k)]
+                                    + 2.0 * gamma * p(k, j - 1)
+                                    + accuracy[(k + 1, j - 1)]
+                    })
+                    .unwrap();
+                structure.add_pair(k, j - 1);
+                regions.push((k + 1, j - 1));
+                j = k;
+            }
+        }
+
+        structure
+    }
+
+    // All pairs with a probability above one half, which are compatible with each other. This is
+    // the structure with the least expected base-pair distance to the ensemble.
+    pub fn centroid(&self) -> Structure {
+        let n = self.unpaired.len();
+        let pairs: Vec<(usize, usize)> = (0..n)
+            .flat_map(|l| self.partners(l).map(move |k| (k, l)))
+            .filter(|(k, l)| self.probabilities[(*k, *l)] > 0.5)
+            .collect();
+
+        Structure::from_pairs(n, &pairs)
+    }
+
+    // Stochastic traceback: draws a structure with probability proportional to its Boltzmann weight.
+    pub fn sample<R: Rng>(&self, rng: &mut R) -> Structure {
+        let n = self.unpaired.len();
+        let mut structure = Structure::new(n);
+        let mut regions = vec![(0, n)];
+
+        while let Some((i, mut j)) = regions.pop() {
+            while j > i {
+                let mut r = rng.gen::<f64>() * self.inside[(i, j)];
+                r -= self.inside[(i, j - 1)] * self.unpaired[j - 1];
+                if r < 0.0 {
+                    j -= 1;
+                    continue;
+                }
+
+                let mut chosen = None;
+                for k in self.partners(j - 1).filter(|k| *k >= i) {
+                    let weight = self.inside[(i, k)] * self.closed[(k, j - 1)];
+                    if weight > 0.0 {
+                        chosen = Some(k);
+                        r -= weight;
+                        if r < 0.0 {
+                            break;
+                        }
+                    }
+                }
+
+                match chosen {
+                    Some(k) => {
+                        structure.add_pair(k, j - 1);
+                        regions.push((k + 1, j - 1));
+                        j = k;
+                    }
+                    None => j -= 1,
+                }
+            }
+        }
+
+        structure
+    }
+
+    // The partners `k` of `l` within the maximal span.
+    fn partners(&self, l: usize) -> std::ops::Range<usize> {
+        l.saturating_sub(self.closed.width())..l
+    }
+}
+
+impl NussinovMatrixBuilder<'_> {
+    // The pairs (i, j) that close a valid linear structure as its only exterior pair, with the
+    // hairpin across the 3'/5' junction shorter than the minimal loop length. None unless the
+    // molecule is circular.
+    pub fn short_circular_hairpins(&self) -> Vec<(usize, usize)> {
+        let n = self.sequence.len();
+        if !self.circular {
+            return vec![];
+        }
+
+        let mut pairs = vec![];
+        for i in 0..self.minimal_loop_length.min(n) {
+            if (0..i).any(|k| !self.can_be_unpaired(k)) {
+                break;
+            }
+            for j in (n.saturating_sub(self.minimal_loop_length - i)..n).rev() {
+                if j <= i {
+                    break;
+                }
+                if self.pair_score(i, j).is_some() {
+                    pairs.push((i, j));
+                }
+                if !self.can_be_unpaired(j) {
+                    break;
+                }
+            }
+        }
+        pairs
+    }
+
+    // In a circular molecule the exterior loop of the linear fill is closed across the 3'/5'
+    // junction. With a single exterior pair it becomes a hairpin that has to respect the minimal
+    // loop length; with none or several exterior pairs every linear structure stays valid.
+    // Expects a root that is not diagonal.
+    fn close_circle(&self, matrix: &Matrix) -> (Trace, usize) {
+        let root = matrix.root_position();
+        let n = root.j;
+
+        let mut bound_before = vec![0; n + 1];
+        for i in 0..n {
+            bound_before[i + 1] = bound_before[i] + usize::from(!self.can_be_unpaired(i));
+        }
+        let all_unpaired =
+            |i: usize, j: usize| bound_before[i] == 0 && bound_before[n] - bound_before[j + 1] == 0;
+
+        let mut possible_traces = vec![];
+        if bound_before[n] == 0 {
+            possible_traces.push((TraceType::Exterior(Position::from(0, 0)), 0));
+        }
+
+        for i in 0..n {
+            for j in (i + 1)..n {
+                let score = match self.pair_score(i, j) {
+                    Some(score) => score,
+                    None => continue,
+                };
+                let inner = Position::from(i + 1, j);
+                if !matrix[inner].is_feasible()
+                    || !all_unpaired(i, j)
+                    || (i + n - 1 - j) < self.minimal_loop_length
+                {
+                    continue;
+                }
+
+                let trace_type = if i == 0 && j == n - 1 {
+                    TraceType::Complementary(inner)
+                } else {
+                    TraceType::Exterior(Position::from(i, j + 1))
+                };
+                possible_traces.push((trace_type, matrix[inner].value() + score));
+            }
+        }
+
+        if self.ambiguous {
+            for k in 1..n {
+                let (pos1, pos2) = root.get_decomposition(k);
+                let (node1, node2) = (&matrix[pos1], &matrix[pos2]);
+                if node1.value() > 0
+                    && node2.value() > 0
+                    && node1.is_feasible()
+                    && node2.is_feasible()
+                {
+                    let trace_type = TraceType::Decomposition(pos1, pos2);
+                    possible_traces.push((trace_type, node1.value() + node2.value()));
+                }
+            }
+        } else {
+            // Several exterior pairs are split off at the last one, after a prefix that pairs.
+            for k in 1..n {
+                let prefix = Position::from(0, k);
+                if matrix[prefix].value() == 0 {
+                    continue;
+                }
+                for l in (k + 1)..n {
+                    if bound_before[n] - bound_before[l + 1] == 0 {
+                        possible_traces.extend(self.get_closed(matrix, prefix, l));
+                    }
+                }
+            }
+        }
+
+        select_max(possible_traces)
+    }
+}
+
+impl NussinovMatrixBuilder<'_> {
+    // With a maximal span only the band around the diagonal is filled. The first row, which
+    // holds every prefix of the sequence, is completed by appending either an unpaired
+    // nucleotide or a band cell to a shorter prefix. This saves time only: the matrix still
+    // allocates every cell of the upper triangle.
+    fn determine_exterior(
+        &self,
+        matrix: &Matrix,
+        position: &Position,
+        max_gap: usize,
+    ) -> (Trace, usize) {
+        if !self.ambiguous {
+            return select_max(self.rules(*position).filter_map(|(t, score)| {
+                let value = self.rule_value(matrix, &t, score)?;
+                Some((t, value))
+            }));
+        }
+
+        let mut possible_traces = vec![self.get_unpaired(matrix, position, UnpairedType::Left)];
+        for k in (position.j - max_gap)..position.j {
+            let (pos1, pos2) = position.get_decomposition(k);
+            let (node1, node2) = (&matrix[pos1], &matrix[pos2]);
+            if node1.is_feasible() && node2.is_feasible() {
+                let trace_type = TraceType::Decomposition(pos1, pos2);
+                possible_traces.push(Some((trace_type, node1.value() + node2.value())));
+            }
+        }
+
+        select_max(possible_traces.into_iter().flatten())
+    }
+
+    // The unambiguous recursion is evaluated over `MaxPlus` without collecting traces; the
+    // textbook recursion keeps its own maximisation.
+    fn fill_cell(
+        &self,
+        matrix: &Matrix,
+        position: Position,
+        max_gap: usize,
+    ) -> Result<MatrixNode, ScoreOverflow> {
+        if !self.ambiguous {
+            let value = |pos: Position| Cow::Owned(max_plus(matrix, pos));
+            let value = self.evaluate_rules(&MaxPlus, position, value, |score| MaxPlus.pair(score));
+            return MatrixNode::from_value(value);
+        }
+
+        let (trace, value) = match position.j - position.i <= max_gap {
+            true => self.determine_max(matrix, &position),
+            false => self.determine_exterior(matrix, &position, max_gap),
+        };
+        MatrixNode::new(&trace, value)
+    }
+
+    // Cells with `j - i <= max_gap` form the band.
+    // The cells of the band by increasing length, then the rest of the first row up to the root.
+    fn cells(&self) -> impl Iterator<Item = Position> {
+        let size = self.sequence.len() + 1;
+        let max_gap = self.max_gap();
+        DiagonalMatrixIterator::new(size, size)
+            .with_max_gap(max_gap)
+            .chain(((max_gap + 1)..size).map(|j| Position::from(0, j)))
+    }
+
+    fn max_gap(&self) -> usize {
+        let n = self.sequence.len();
+        self.max_span.map_or(n + 1, |max_span| max_span + 1)
+    }
+
+    // The partners `k` of `l` that are within the maximal span.
+    fn partners(&self, l: usize) -> std::ops::Range<usize> {
+        let first = self
+            .max_span
+            .map_or(0, |max_span| l.saturating_sub(max_span));
+        first..l
     }
 }
 
 impl MatrixBuilder for NussinovMatrixBuilder<'_> {
-    fn fill<'m>(&self, matrix: &'m mut Matrix) {
-        let j = matrix.columns();
-        let i = matrix.rows();
-        let diagonal_iter = DiagonalMatrixIterator::new(j, i);
+    fn fill(&self, matrix: &mut Matrix) -> Result<(), ScoreOverflow> {
+        let max_gap = self.max_gap();
+        for position in self.cells() {
+            matrix[position] = self.fill_cell(matrix, position, max_gap)?;
+        }
 
-        for position in diagonal_iter {
-            let (trace, value) = self.determine_max(matrix, &position);
-            let node = MatrixNode {
-                trace,
-                position,
-                value,
-            };
-            matrix[position] = node;
+        let root = matrix.root_position();
+        if self.circular && !root.is_diagonal() {
+            let (trace, value) = self.close_circle(matrix);
+            matrix[root] = MatrixNode::new(&trace, value)?;
         }
+        Ok(())
     }
+
+    fn traces(&self, matrix: &Matrix, position: Position) -> Trace {
+        let max_gap = self.max_gap();
+        if position.is_diagonal() {
+            vec![]
+        } else if self.circular && position == matrix.root_position() {
+            self.close_circle(matrix).0
+        } else if position.j - position.i <= max_gap {
+            self.determine_max(matrix, &position).0
+        } else if position.i == 0 {
+            self.determine_exterior(matrix, &position, max_gap).0
+        } else {
+            vec![]
+        }
+    }
+}
+
+// Keeps every trace reaching the highest value.
+fn select_max(possible_traces: impl IntoIterator<Item = (TraceType, usize)>) -> (Trace, usize) {
+    let possible_traces: Vec<(TraceType, usize)> = possible_traces.into_iter().collect();
+    let max = possible_traces.iter().map(|(_, v)| *v).max().unwrap_or(0);
+    let trace = possible_traces
+        .into_iter()
+        .filter(|(_, v)| *v == max)
+        .map(|(trace_type, _)| trace_type)
+        .collect();
+
+    (trace, max)
+}
+
+// Value of a rule of `NussinovMatrixBuilder::rules` over a semiring.
+fn derive<'v, S: Semiring>(
+    semiring: &S,
+    trace_type: &TraceType,
+    unpaired: &S::Value,
+    pair: impl FnOnce() -> S::Value,
+    value: &impl Fn(Position) -> Cow<'v, S::Value>,
+) -> S::Value
+where
+    S::Value: 'v,
+{
+    match trace_type {
+        TraceType::Unpaired(pos) => semiring.mul(&value(*pos), unpaired),
+        TraceType::Closed(prefix, inner) => {
+            let closed = semiring.mul(&pair(), &value(*inner));
+            semiring.mul(&value(*prefix), &closed)
+        }
+        _ => unreachable!("`rules` only derives unpaired and closed cells"),
+    }
+}
+
+// A filled cell as a value of the `MaxPlus` semiring.
+fn max_plus(matrix: &Matrix, position: Position) -> Option<usize> {
+    let node = &matrix[position];
+    node.is_feasible().then_some(node.value())
 }
 
 impl DiagonalMatrixIterator {
@@ -152,8 +894,15 @@ impl DiagonalMatrixIterator {
             rows,
             position: 0,
             gap: 1,
+            max_gap: columns,
         }
     }
+
+    // Restricts the iterator to the band of cells with `j - i <= max_gap`.
+    pub fn with_max_gap(mut self, max_gap: usize) -> DiagonalMatrixIterator {
+        self.max_gap = max_gap;
+        self
+    }
 }
 
 impl Iterator for DiagonalMatrixIterator {
@@ -164,7 +913,7 @@ impl Iterator for DiagonalMatrixIterator {
             self.position = 0;
             self.gap += 1;
         }
-        if self.gap >= self.columns {
+        if self.gap >= self.columns || self.gap > self.max_gap {
             return None;
         }
         let i = self.position;
@@ -178,3 +927,520 @@ impl Iterator for DiagonalMatrixIterator {
         Some(Position::from(i, j))
     }
 }
+
+// Extends the unambiguous Nussinov recursion by H-type pseudoknots, whose two stems of stacked
+// pairs cross: the first stem pairs the 5' end of the knot with the middle, the second the middle
+// with its 3' end. The three loops between the strands contain nested structures, which may be
+// knotted again. Finding the best knot of a cell tries O(n^2) choices of the stem ends times
+// O(n^2) stem lengths, so folding needs O(n^6) time in the worst case. A stem stops growing at
+// the first pair that cannot form, which keeps real sequences far below that bound.
+pub struct PseudoknotMatrixBuilder<'s> {
+    nested: NussinovMatrixBuilder<'s>,
+    knots: RefCell<Triangle<Option<Knot>>>,
+}
+
+// Stems pairing (i + t, `l` - t) for t < `a` and (`k` + t, j - 1 - t) for t < `b` in the cell (i, j).
+#[derive(Clone, Copy, Debug)]
+struct Knot {
+    value: usize,
+    k: usize,
+    l: usize,
+    a: usize,
+    b: usize,
+}
+
+impl<'s> PseudoknotMatrixBuilder<'s> {
+    // Stems of single pairs would let nearly every pair of a sequence cross some other pair.
+    pub const MIN_STEM_LENGTH: usize = 2;
+
+    // Pairs are scored and constrained by `nested`, whose circularity and ambiguous recursion are
+    // ignored. A maximal span still restricts the pairs but does not shrink the filled cells.
+    pub fn new(nested: NussinovMatrixBuilder<'s>) -> PseudoknotMatrixBuilder<'s> {
+        let size = nested.sequence.len() + 1;
+        PseudoknotMatrixBuilder {
+            nested,
+            knots: RefCell::new(Triangle::filled_with(None, size)),
+        }
+    }
+
+    // The structure of the first trace of every cell, which may contain crossing pairs.
+    pub fn structure(&self, matrix: &Matrix) -> Structure {
+        let knots = self.knots.borrow();
+        let root = matrix.root_position();
+        let mut pairs = vec![];
+        let mut pending = vec![root];
+
+        while let Some(position) = pending.pop() {
+            match self.traces(matrix, position).first() {
+                Some(TraceType::Unpaired(pos)) => pending.push(*pos),
+                Some(TraceType::Closed(prefix, inner)) => {
+                    pairs.push((prefix.j, inner.j));
+                    pending.extend([*prefix, *inner]);
+                }
+                Some(TraceType::Pseudoknot(prefix, knot)) => {
+                    let Knot { k, l, a, b, .. } = knots[(*knot).into()].unwrap();
+                    let (i, j) = (knot.i, knot.j);
+                    pairs.extend((0..a).map(|t| (i + t, l - t)));
+                    pairs.extend((0..b).map(|t| (k + t, j - 1 - t)));
+                    pending.extend([
+                        *prefix,
+                        Position::from(i + a, k),
+                        Position::from(k + b, l + 1 - a),
+                        Position::from(l + 1, j - b),
+                    ]);
+                }
+                Some(_) => unreachable!("Pseudoknot fills only use unambiguous traces"),
+                None => {}
+            }
+        }
+
+        Structure::from_pairs(root.j, &pairs)
+    }
+
+    // The best knot spanning exactly the cell. Every loop is at least one nucleotide long, except
+    // for the one between both strands in the middle.
+    fn best_knot(&self, matrix: &Matrix, pos: &Position) -> Option<Knot> {
+        let (i, j) = (pos.i, pos.j);
+        let value = |i: usize, j: usize| {
+            let node = &matrix[Position::from(i, j)];
+            node.is_feasible().then_some(node.value())
+        };
+        let mut best: Option<Knot> = None;
+
+        for l in (i + 1)..j {
+            let mut stem_a = 0;
+            for a in 1..=(l - i).div_ceil(2) {
+                stem_a += match self.nested.pair_score(i + a - 1, l + 1 - a) {
+                    Some(score) => score,
+                    None => break,
+                };
+                if a < Self::MIN_STEM_LENGTH {
+                    continue;
+                }
+
+                for k in (i + a + 1)..(l + 1 - a) {
+                    let first_loop = match value(i + a, k) {
+                        Some(v) => v,
+                        None => continue,
+                    };
+                    let mut stem_b = 0;
+                    for b in 1..=(l + 1 - a - k).min(j.saturating_sub(l + 2)) {
+                        stem_b += match self.nested.pair_score(k + b - 1, j - b) {
+                            Some(score) => score,
+                            None => break,
+                        };
+                        if b < Self::MIN_STEM_LENGTH {
+                            continue;
+                        }
+                        let loops = value(k + b, l + 1 - a)
+                            .zip(value(l + 1, j - b))
+                            .map(|(middle, last)| first_loop + middle + last);
+                        if let Some(loops) = loops {
+                            let knot_value = stem_a + stem_b + loops;
+                            if best.is_none_or(|knot| knot.value < knot_value) {
+                                best = Some(Knot {
+                                    value: knot_value,
+                                    k,
+                                    l,
+                                    a,
+                                    b,
+                                });
+                            }
+                        }
+                    }
+                }
+            }
+        }
+
+        best
+    }
+
+    fn determine_max(
+        &self,
+        matrix: &Matrix,
+        knots: &Triangle<Option<Knot>>,
+        pos: &Position,
+    ) -> (Trace, usize) {
+        let nested = self.nested.rules(*pos).filter_map(|(trace_type, score)| {
+            let value = self.nested.rule_value(matrix, &trace_type, score)?;
+            Some((trace_type, value))
+        });
+        let knotted = (pos.i..(pos.j - 1)).filter_map(|m| {
+            let prefix = Position::from(pos.i, m);
+            let knot = Position::from(m, pos.j);
+            let value = max_plus(matrix, prefix)? + knots[knot.into()]?.value;
+            Some((TraceType::Pseudoknot(prefix, knot), value))
+        });
+
+        // Co-optimal traces are ordered by their split point, which decides the traced structure.
+        let mut possible_traces: Vec<(TraceType, usize)> = nested.chain(knotted).collect();
+        possible_traces.sort_by_key(|(trace_type, _)| match trace_type {
+            TraceType::Closed(prefix, _) => Some((prefix.j, 0)),
+            TraceType::Pseudoknot(prefix, _) => Some((prefix.j, 1)),
+            _ => None,
+        });
+
+        select_max(possible_traces)
+    }
+}
+
+impl MatrixBuilder for PseudoknotMatrixBuilder<'_> {
+    fn fill(&self, matrix: &mut Matrix) -> Result<(), ScoreOverflow> {
+        let j = matrix.columns();
+        let i = matrix.rows();
+        let diagonal_iter = DiagonalMatrixIterator::new(j, i);
+        let mut knots = self.knots.borrow_mut();
+
+        for position in diagonal_iter {
+            knots[position.into()] = self.best_knot(matrix, &position);
+
+            let (trace, value) = self.determine_max(matrix, &knots, &position);
+            matrix[position] = MatrixNode::new(&trace, value)?;
+        }
+        Ok(())
+    }
+
+    fn traces(&self, matrix: &Matrix, position: Position) -> Trace {
+        if position.is_diagonal() {
+            return vec![];
+        }
+        self.determine_max(matrix, &self.knots.borrow(), &position)
+            .0
+    }
+}
+
+pub struct ZukerMatrixBuilder<'s> {
+    sequence: &'s RNASequence,
+    loops: RefCell<LoopMatrices>,
+}
+
+pub struct LoopMatrices {
+    pub closed: Triangle<i32>,
+    pub multi: Triangle<i32>,
+}
+
+impl<'s> ZukerMatrixBuilder<'s> {
+    pub fn new(sequence: &'s RNASequence) -> ZukerMatrixBuilder<'s> {
+        let size = sequence.len() + 1;
+        ZukerMatrixBuilder {
+            sequence,
+            loops: RefCell::new(LoopMatrices {
+                closed: Triangle::filled_with(INF, size),
+                multi: Triangle::filled_with(INF, size),
+            }),
+        }
+    }
+
+    pub fn loops(&self) -> Ref<'_, LoopMatrices> {
+        self.loops.borrow()
+    }
+
+    pub fn pair_type(&self, pos: &Position) -> Option<usize> {
+        energy_parameters::pair_type(self.sequence[pos.i], self.sequence[pos.j - 1])
+    }
+
+    // Energy of the loops closed by the pair (pos.i, pos.j - 1), assuming all inner cells are known.
+    pub fn closed_energies(&self, loops: &LoopMatrices, pos: &Position) -> Vec<(ClosedLoop, i32)> {
+        if pos.j < pos.i + 2 + energy_parameters::MIN_HAIRPIN {
+            return vec![];
+        }
+        let outer = match self.pair_type(pos) {
+            Some(t) => t,
+            None => return vec![],
+        };
+        let mut energies = vec![(
+            ClosedLoop::Hairpin,
+            energy_parameters::hairpin(outer, pos.j - pos.i - 2),
+        )];
+
+        for k in (pos.i + 1)..(pos.j - 1) {
+            let left = k - pos.i - 1;
+            if left > energy_parameters::MAX_LOOP {
+                break;
+            }
+            for l in ((k + 1)..(pos.j - 1)).rev() {
+                let right = pos.j - 2 - l;
+                if left + right > energy_parameters::MAX_LOOP {
+                    break;
+                }
+                let inner_pos = Position::from(k, l + 1);
+                let inner_energy = loops.closed[inner_pos.into()];
+                if inner_energy >= INF {
+                    continue;
+                }
+                if let Some(inner) =
+                    energy_parameters::pair_type(self.sequence[l], self.sequence[k])
+                {
+                    let energy = energy_parameters::interior(outer, inner, left, right);
+                    energies.push((ClosedLoop::Interior(inner_pos), energy + inner_energy));
+                }
+            }
+        }
+
+        let closing = energy_parameters::MULTILOOP_CLOSING
+            + energy_parameters::MULTILOOP_BRANCH
+            + energy_parameters::terminal_penalty(outer);
+        for u in (pos.i + 2)..(pos.j - 1) {
+            let first = Position::from(pos.i + 1, u);
+            let second = Position::from(u, pos.j - 1);
+            let energy = loops.multi[first.into()] + loops.multi[second.into()];
+            if energy < INF {
+                energies.push((ClosedLoop::Multi(first, second), closing + energy));
+            }
+        }
+
+        energies
+    }
+
+    pub fn multi_energies(&self, loops: &LoopMatrices, pos: &Position) -> Vec<(MultiLoop, i32)> {
+        let mut energies = vec![];
+
+        let closed = loops.closed[pos.into()];
+        if closed < INF {
+            let branch = energy_parameters::MULTILOOP_BRANCH
+                + energy_parameters::terminal_penalty(self.pair_type(pos).unwrap());
+            energies.push((MultiLoop::Branch, closed + branch));
+        }
+
+        for unpaired_type in [UnpairedType::Left, UnpairedType::Bottom] {
+            let unpaired = pos.get_unpaired(unpaired_type);
+            if !unpaired.is_diagonal() {
+                let energy = loops.multi[unpaired.into()] + energy_parameters::MULTILOOP_UNPAIRED;
+                energies.push((MultiLoop::Unpaired(unpaired), energy));
+            }
+        }
+
+        for k in (pos.i + 1)..pos.j {
+            let (first, second) = pos.get_decomposition(k);
+            let energy = loops.multi[first.into()] + loops.multi[second.into()];
+            energies.push((MultiLoop::Decomposition(first, second), energy));
+        }
+
+        energies.retain(|(_, energy)| *energy < INF);
+        energies
+    }
+
+    fn determine_min(
+        &self,
+        matrix: &Matrix,
+        loops: &LoopMatrices,
+        pos: &Position,
+    ) -> (Trace, usize) {
+        let mut possible_traces = vec![];
+
+        for unpaired_type in [UnpairedType::Left, UnpairedType::Bottom] {
+            let unpaired = pos.get_unpaired(unpaired_type);
+            let energy = -(matrix[unpaired].value() as i32);
+            possible_traces.push((TraceType::Unpaired(unpaired), energy));
+        }
+
+        let closed = loops.closed[pos.into()];
+        if closed < INF {
+            let penalty = energy_parameters::terminal_penalty(self.pair_type(pos).unwrap());
+            let trace_type = TraceType::Complementary(pos.get_complementary());
+            possible_traces.push((trace_type, closed + penalty));
+        }
+
+        for k in (pos.i + 1)..pos.j {
+            let (pos1, pos2) = pos.get_decomposition(k);
+            let value = matrix[pos1].value() + matrix[pos2].value();
+            possible_traces.push((TraceType::Decomposition(pos1, pos2), -(value as i32)));
+        }
+
+        let min = possible_traces
+            .iter()
+            .map(|(_, energy)| *energy)
+            .min()
+            .unwrap_or(0)
+            .min(0);
+
+        let trace = possible_traces
+            .into_iter()
+            .filter(|(_, energy)| *energy == min)
+            .map(|(trace_type, _)| trace_type)
+            .collect();
+
+        (trace, (-min) as usize)
+    }
+}
+
+pub enum ClosedLoop {
+    Hairpin,
+    Interior(Position),
+    Multi(Position, Position),
+}
+
+pub enum MultiLoop {
+    Branch,
+    Unpaired(Position),
+    Decomposition(Position, Position),
+}
+
+impl MatrixBuilder for ZukerMatrixBuilder<'_> {
+    fn fill(&self, matrix: &mut Matrix) -> Result<(), ScoreOverflow> {
+        let j = matrix.columns();
+        let i = matrix.rows();
+        let diagonal_iter = DiagonalMatrixIterator::new(j, i);
+        let mut loops = self.loops.borrow_mut();
+
+        for position in diagonal_iter {
+            let closed = self
+                .closed_energies(&loops, &position)
+                .into_iter()
+                .map(|(_, energy)| energy)
+                .min()
+                .unwrap_or(INF);
+            loops.closed[position.into()] = closed.min(INF);
+
+            let multi = self
+                .multi_energies(&loops, &position)
+                .into_iter()
+                .map(|(_, energy)| energy)
+                .min()
+                .unwrap_or(INF);
+            loops.multi[position.into()] = multi.min(INF);
+
+            let (trace, value) = self.determine_min(matrix, &loops, &position);
+            matrix[position] = MatrixNode::new(&trace, value)?;
+        }
+        Ok(())
+    }
+
+    fn traces(&self, matrix: &Matrix, position: Position) -> Trace {
+        if position.is_diagonal() {
+            return vec![];
+        }
+        self.determine_min(matrix, &self.loops.borrow(), &position)
+            .0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use crate::{
+        brute_force,
+        constraint::Constraint,
+        matrix::{Matrix, MatrixNode},
+        matrix_builder::MatrixBuilder,
+        nussinov::{RNASequence, KT},
+        pair_scoring::TableScoring,
+        path_converter::{NussinovPathConverter, PathConverter},
+        traceback_paths::{TracebackPathsBuilder, ZukerTracebackPathsBuilder},
+    };
+
+    use super::{NussinovMatrixBuilder, PseudoknotMatrixBuilder, ZukerMatrixBuilder};
+
+    fn assert_close(actual: f64, expected: f64) {
+        assert!(
+            (actual - expected).abs() <= 1e-9 * expected.abs().max(1.0),
+            "{} != {}",
+            actual,
+            expected
+        );
+    }
+
+    // Compares the partition function with the Boltzmann weights of all enumerated structures.
+    fn check_partition_function(matrix_builder: &NussinovMatrixBuilder) {
+        let (matrix, structures) = brute_force::fill(matrix_builder);
+        let partition_function = matrix_builder.partition_function(&matrix, KT);
+
+        let weight = |score: usize| (score as f64 / KT).exp();
+        let total: f64 = structures.iter().map(|(_, score)| weight(*score)).sum();
+        assert_close(partition_function.ensemble_free_energy(), -KT * total.ln());
+
+        let n = matrix.root_position().j;
+        for i in 0..n {
+            for j in (i + 1)..n {
+                let paired: f64 = structures
+                    .iter()
+                    .filter(|(structure, _)| structure.partner(i) == Some(j))
+                    .map(|(_, score)| weight(*score))
+                    .sum();
+                assert_close(partition_function.pair_probability(i, j), paired / total);
+                assert_close(partition_function.pair_probability(j, i), paired / total);
+            }
+        }
+    }
+
+    #[test]
+    fn partition_function_matches_enumeration() {
+        for sequence in brute_force::SEQUENCES {
+            let sequence = RNASequence::new(sequence).unwrap();
+            for matrix_builder in brute_force::builders(&sequence) {
+                check_partition_function(&matrix_builder);
+            }
+        }
+    }
+
+    #[test]
+    fn partition_function_respects_constraints() {
+        let sequence = RNASequence::new("GGGAAAUCCAAG").unwrap();
+        let constraint = Constraint::new("<..(..)x....").unwrap();
+        let matrix_builder = NussinovMatrixBuilder::new(&sequence, 1).with_constraint(&constraint);
+        check_partition_function(&matrix_builder);
+    }
+
+    #[test]
+    fn mea_pairs_nucleotides_forced_to_pair() {
+        let sequence = RNASequence::new("GGGAAAUCCAAAA").unwrap();
+        let constraint = Constraint::new("<............").unwrap();
+        let matrix_builder = NussinovMatrixBuilder::new(&sequence, 1).with_constraint(&constraint);
+        let mut matrix = Matrix::new(sequence.len());
+        matrix_builder.fill(&mut matrix).unwrap();
+        let partition_function = matrix_builder.partition_function(&matrix, KT);
+
+        for gamma in [0.01, 1.0, 10.0] {
+            assert!(partition_function.mea(gamma).partner(0).is_some());
+        }
+    }
+
+    #[test]
+    fn overflowing_scores_fail_the_fill() {
+        let sequence = RNASequence::new("GGAAACC").unwrap();
+        // Two pairs of the first score just overflow a cell; the second does not fit the cache.
+        for score in [MatrixNode::MAX.value() / 2 + 1, usize::MAX / 4] {
+            let scoring = TableScoring::new(HashMap::from([(('G', 'C'), score)]));
+            let matrix_builder = NussinovMatrixBuilder::new(&sequence, 1).with_scoring(&scoring);
+            assert_eq!(matrix_builder.pair_score(0, 6), Some(score));
+
+            let mut matrix = matrix_builder.new_matrix();
+            assert!(matrix_builder.fill(&mut matrix).is_err());
+        }
+    }
+
+    #[test]
+    fn pseudoknot_folds_an_h_type_knot() {
+        // GGGG pairs with CCCC and AGAG with CUCU, whose strands lie on either side of CCCC.
+        // Nested structures reach at most 7 pairs.
+        let sequence = RNASequence::new("GGGGAAAGAGACCCCAACUCU").unwrap();
+        let nested = NussinovMatrixBuilder::new(&sequence, 1);
+        let mut matrix = nested.new_matrix();
+        nested.fill(&mut matrix).unwrap();
+        assert_eq!(matrix.root().value(), 7);
+
+        let matrix_builder = PseudoknotMatrixBuilder::new(nested);
+        let mut matrix = Matrix::new(sequence.len());
+        matrix_builder.fill(&mut matrix).unwrap();
+        assert_eq!(matrix.root().value(), 8);
+
+        let structure = matrix_builder.structure(&matrix);
+        let structure = NussinovPathConverter::new(&sequence).convert_structure(&structure);
+        assert_eq!(structure, "((((..[[[[.))))..]]]]");
+    }
+
+    #[test]
+    fn zuker_folds_a_stacked_hairpin() {
+        // Three GC/CG stacks of -3.3 each on a GAAAC triloop of 5.4, with no terminal penalty.
+        let sequence = RNASequence::new("GGGGAAACCCC").unwrap();
+        let matrix_builder = ZukerMatrixBuilder::new(&sequence);
+        let mut matrix = Matrix::new(sequence.len());
+        matrix_builder.fill(&mut matrix).unwrap();
+        assert_eq!(matrix.root().value(), 3 * 330 - 540);
+
+        let paths = ZukerTracebackPathsBuilder::new(&matrix_builder).build(&matrix);
+        let structures = NussinovPathConverter::new(&sequence).convert(&paths);
+        assert_eq!(structures, vec!["((((...))))".to_string()]);
+    }
+}
@@ -1,104 +1,544 @@
-use std::ops::Index;
-
-use crate::{
-    matrix::{Matrix, Position},
-    matrix_builder::NussinovMatrixBuilder,
-    path_converter::NussinovPathConverter,
-    path_converter::PathConverter,
-    traceback_paths::{NussinovTracebackPathsBuilder, TracebackPathsBuilder},
-};
-
-pub struct Nussinov {
-    minimal_loop_length: usize,
-    sequence: RNASequence,
-    matrix: Matrix,
-}
-
-// TODO remove Clone
-#[derive(Debug, Clone)]
-pub struct RNASequence(Vec<char>);
-
-impl Nussinov {
-    pub fn new(sequence: &str, minimal_loop_length: usize) -> Nussinov {
-        let deserialized_sequence = RNASequence::new(sequence);
-        match deserialized_sequence {
-            Ok(s) => Nussinov {
-                minimal_loop_length,
-                matrix: Matrix::new(s.len()),
-                sequence: s,
-            },
-            Err(_) => panic!("The given RNA-sequence is invalid"),
-        }
-    }
-
-    pub fn run(&mut self) {
-        println!("Analysing sequence: {:?}", self.sequence);
-        println!();
-        let matrix_builder = NussinovMatrixBuilder::new(&self.sequence, self.minimal_loop_length);
-        self.matrix.fill(&matrix_builder);
-
-        print!("{}", self.matrix);
-
-        let traceback_builder = NussinovTracebackPathsBuilder::new();
-        let paths = traceback_builder.build(&self.matrix);
-
-        let path_converter = NussinovPathConverter::new(&self.sequence);
-        let symbolic_paths = path_converter.convert(&paths);
-
-        println!("{:#?}", symbolic_paths);
-    }
-}
-
-impl RNASequence {
-    const VALID_CHARS: [char; 4] = ['A', 'U', 'G', 'C'];
-    const COMPLEMENTS: [[char; 2]; 3] = [['A', 'U'], ['G', 'C'], ['G', 'U']];
-
-    pub fn new(rna_sequence: &str) -> Result<RNASequence, ()> {
-        let sequence = rna_sequence.to_uppercase();
-        if !RNASequence::is_valid(&sequence) {
-            return Err(());
-        }
-
-        Ok(RNASequence(sequence.chars().collect()))
-    }
-
-    fn is_valid(sequence: &str) -> bool {
-        sequence
-            .chars()
-            .all(|c| RNASequence::VALID_CHARS.contains(&c))
-    }
-
-    pub fn len(&self) -> usize {
-        self.0.len()
-    }
-
-    pub fn is_complement(&self, position: &Position) -> Result<bool, ()> {
-        let size = self.len();
-        let i = position.i;
-        let j = position.j - 1;
-
-        if i >= size || j >= size {
-            return Err(());
-        }
-        let first_nucleotid = self.0[j];
-        let second_nucleotid = self.0[i];
-
-        let complement = RNASequence::COMPLEMENTS
-            .iter()
-            .map(|c| {
-                (c.contains(&first_nucleotid) && c.contains(&second_nucleotid))
-                    && first_nucleotid != second_nucleotid
-            })
-            .fold(false, |c, n| c || n);
-
-        Ok(complement)
-    }
-}
-
-impl Index<usize> for &RNASequence {
-    type Output = char;
-
-    fn index(&self, index: usize) -> &Self::Output {
-        &self.0[index]
-    }
-}
+use std::{fmt, ops::Index};
+
+use clap::ValueEnum;
+use num_bigint::BigUint;
+use rand::{rngs::StdRng, SeedableRng};
+
+use crate::{
+    adp::{Algebra, Grammar},
+    constraint::Constraint,
+    counting::{PathCounts, StructureCounter},
+    kbest::KBestBuilder,
+    matrix::{Matrix, ScoreOverflow},
+    matrix_builder::{
+        NussinovMatrixBuilder, PartitionFunction, PseudoknotMatrixBuilder, ZukerMatrixBuilder,
+    },
+    pair_scoring::{PairScoring, UnitScoring},
+    path_converter::NussinovPathConverter,
+    path_converter::PathConverter,
+    semiring::Semiring,
+    shape::ShapeData,
+    suboptimal::SuboptimalBuilder,
+    traceback_paths::{OptimalStructures, TracebackPathsBuilder, ZukerTracebackPathsBuilder},
+};
+
+// Thermal energy at 37 °C in kcal/mol; pair scores are read as stabilising energies.
+pub(crate) const KT: f64 = 0.61632;
+
+pub struct Nussinov {
+    minimal_loop_length: usize,
+    sequence: RNASequence,
+    // Filled on demand; the previous matrix is dropped before the next one is allocated.
+    matrix: Option<Matrix>,
+    scoring: Box<dyn PairScoring>,
+    algorithm: Algorithm,
+    constraint: Option<Constraint>,
+    shape: Option<ShapeData>,
+    circular: bool,
+    max_span: Option<usize>,
+    ambiguous: bool,
+    max_structures: Option<usize>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum Algorithm {
+    Nussinov,
+    Zuker,
+    // Nussinov scoring with H-type pseudoknots.
+    Pseudoknot,
+}
+
+// TODO remove Clone
+#[derive(Clone)]
+pub struct RNASequence {
+    nucleotides: Vec<char>,
+    strand_break: Option<usize>,
+}
+
+#[derive(Debug)]
+pub struct InvalidSequence;
+
+impl Nussinov {
+    pub fn new(sequence: &str, minimal_loop_length: usize) -> Nussinov {
+        let deserialized_sequence = RNASequence::new(sequence);
+        match deserialized_sequence {
+            Ok(s) => Nussinov {
+                minimal_loop_length,
+                matrix: None,
+                sequence: s,
+                scoring: Box::new(UnitScoring),
+                algorithm: Algorithm::Nussinov,
+                constraint: None,
+                shape: None,
+                circular: false,
+                max_span: None,
+                ambiguous: false,
+                max_structures: None,
+            },
+            Err(_) => panic!("The given RNA-sequence is invalid"),
+        }
+    }
+
+    pub fn with_scoring(mut self, scoring: Box<dyn PairScoring>) -> Nussinov {
+        self.scoring = scoring;
+        self
+    }
+
+    pub fn with_algorithm(mut self, algorithm: Algorithm) -> Nussinov {
+        self.algorithm = algorithm;
+        self
+    }
+
+    pub fn with_constraint(mut self, constraint: Constraint) -> Result<Nussinov, String> {
+        if constraint.len() != self.sequence.len() {
+            return Err("The constraint does not match the length of the RNA-sequence".to_string());
+        }
+        self.constraint = Some(constraint);
+        Ok(self)
+    }
+
+    pub fn with_shape(mut self, shape: ShapeData) -> Result<Nussinov, String> {
+        if shape.len() > self.sequence.len() {
+            return Err("The SHAPE data does not match the length of the RNA-sequence".to_string());
+        }
+        self.shape = Some(shape);
+        Ok(self)
+    }
+
+    // The optimal structures and the counts honour circularity; the ensemble methods, grammars and
+    // the suboptimal and k-best structures fold the sequence linearly and are not offered for it.
+    pub fn with_circular(mut self, circular: bool) -> Result<Nussinov, String> {
+        if circular && self.sequence.strand_break().is_some() {
+            return Err("Two strands cannot form a circular molecule".to_string());
+        }
+        self.circular = circular;
+        Ok(self)
+    }
+
+    pub fn with_max_span(mut self, max_span: Option<usize>) -> Nussinov {
+        self.max_span = max_span;
+        self
+    }
+
+    // Fills the matrix with the textbook recursion, which prints structures once per derivation.
+    pub fn with_ambiguous(mut self, ambiguous: bool) -> Nussinov {
+        self.ambiguous = ambiguous;
+        self
+    }
+
+    // Limits the number of optimal structures printed by `run`.
+    pub fn with_max_structures(mut self, max_structures: Option<usize>) -> Nussinov {
+        self.max_structures = max_structures;
+        self
+    }
+
+    pub fn run(&mut self) {
+        println!("Analysing sequence: {:?}", self.sequence);
+        println!();
+
+        let max_structures = self.max_structures.unwrap_or(usize::MAX);
+        match self.algorithm {
+            Algorithm::Nussinov => {
+                if let Err(e) = self.fill(self.circular) {
+                    println!("{}", e);
+                    return;
+                }
+                print!("{}", self.matrix());
+
+                if !self.matrix().root().is_feasible() {
+                    println!("The constraint cannot be satisfied");
+                    return;
+                }
+
+                print_structures(self.optimal_structures().take(max_structures));
+            }
+            Algorithm::Zuker => match self.zuker_restriction() {
+                Some(restriction) => println!("{}", restriction),
+                None => {
+                    let structures = match self.zuker_structures() {
+                        Ok(structures) => structures,
+                        Err(e) => {
+                            println!("{}", e);
+                            return;
+                        }
+                    };
+                    print!("{}", self.matrix());
+
+                    // The open chain has no energy rather than -0.
+                    let energy = match self.matrix().root().value() {
+                        0 => 0.0,
+                        value => -(value as f64) / 100.0,
+                    };
+                    println!("Minimum free energy: {:.2} kcal/mol", energy);
+                    println!();
+
+                    print_structures(structures.into_iter().take(max_structures));
+                }
+            },
+            Algorithm::Pseudoknot if self.circular => {
+                println!("Pseudoknot folding does not support circular molecules");
+            }
+            Algorithm::Pseudoknot => {
+                let structure = match self.pseudoknot_structure() {
+                    Ok(structure) => structure,
+                    Err(e) => {
+                        println!("{}", e);
+                        return;
+                    }
+                };
+                print!("{}", self.matrix());
+
+                if !self.matrix().root().is_feasible() {
+                    println!("The constraint cannot be satisfied");
+                    return;
+                }
+
+                print_structures(std::iter::once(structure));
+            }
+        }
+    }
+
+    // Every optimal structure in dot-bracket notation, traced lazily one at a time; none if the
+    // sequence cannot be folded. Every method filling a matrix fails if a score overflows it.
+    pub fn structures(&mut self) -> Result<Box<dyn Iterator<Item = String> + '_>, ScoreOverflow> {
+        let structures: Box<dyn Iterator<Item = String>> = match self.algorithm {
+            Algorithm::Nussinov => {
+                self.fill(self.circular)?;
+                Box::new(self.optimal_structures())
+            }
+            Algorithm::Zuker if self.zuker_restriction().is_some() => Box::new(std::iter::empty()),
+            Algorithm::Zuker => Box::new(self.zuker_structures()?.into_iter()),
+            Algorithm::Pseudoknot => {
+                let structure = self.pseudoknot_structure()?;
+                match self.matrix().root().is_feasible() {
+                    true => Box::new(std::iter::once(structure)),
+                    false => Box::new(std::iter::empty()),
+                }
+            }
+        };
+        Ok(structures)
+    }
+
+    pub fn partition_function(&mut self) -> Result<PartitionFunction, ScoreOverflow> {
+        self.fill(false)?;
+        Ok(self.matrix_builder().partition_function(self.matrix(), KT))
+    }
+
+    // `None` if the constraint cannot be satisfied, here and for the other ensemble methods.
+    pub fn sample(&mut self, n: usize, seed: u64) -> Result<Option<Vec<String>>, ScoreOverflow> {
+        let partition_function = self.partition_function()?;
+        if !self.matrix().root().is_feasible() {
+            return Ok(None);
+        }
+        let mut rng = StdRng::seed_from_u64(seed);
+        let path_converter = NussinovPathConverter::new(&self.sequence);
+
+        let structures = (0..n)
+            .map(|_| path_converter.convert_structure(&partition_function.sample(&mut rng)))
+            .collect();
+        Ok(Some(structures))
+    }
+
+    // One of the distinct optimal structures, each with the same probability; `None` if the
+    // sequence cannot be folded. The matrix is always filled unambiguously for this.
+    pub fn random_optimal(&mut self, seed: u64) -> Result<Option<String>, ScoreOverflow> {
+        self.matrix = None;
+        let matrix_builder = self
+            .matrix_builder()
+            .with_circular(self.circular)
+            .with_ambiguous(false);
+        let mut matrix = matrix_builder.new_matrix();
+        matrix.fill(&matrix_builder)?;
+
+        let mut rng = StdRng::seed_from_u64(seed);
+        let path_converter = NussinovPathConverter::new(&self.sequence);
+        let structure = PathCounts::new(&matrix, &matrix_builder)
+            .sample(&mut rng)
+            .map(|structure| path_converter.convert_structure(&structure));
+
+        self.matrix = Some(matrix);
+        Ok(structure)
+    }
+
+    pub fn mea(&mut self, gamma: f64) -> Result<Option<String>, ScoreOverflow> {
+        let partition_function = self.partition_function()?;
+        if !self.matrix().root().is_feasible() {
+            return Ok(None);
+        }
+        let structure = partition_function.mea(gamma);
+        Ok(Some(
+            NussinovPathConverter::new(&self.sequence).convert_structure(&structure),
+        ))
+    }
+
+    pub fn centroid(&mut self) -> Result<Option<String>, ScoreOverflow> {
+        let partition_function = self.partition_function()?;
+        if !self.matrix().root().is_feasible() {
+            return Ok(None);
+        }
+        let structure = partition_function.centroid();
+        Ok(Some(
+            NussinovPathConverter::new(&self.sequence).convert_structure(&structure),
+        ))
+    }
+
+    // Evaluates all secondary structures of the linear sequence over a semiring, e.g. `Counting`.
+    pub fn evaluate<S: Semiring>(&self, semiring: &S) -> S::Value {
+        let n = self.sequence.len();
+        self.matrix_builder().inside(semiring)[(0, n)].clone()
+    }
+
+    // Every chosen candidate of the linear sequence under a user-defined grammar and algebra.
+    pub fn evaluate_grammar<A: Algebra>(&self, grammar: &Grammar, algebra: &A) -> Vec<A::Value> {
+        grammar.evaluate(&self.matrix_builder(), self.sequence.len(), algebra)
+    }
+
+    // Number of distinct optimal structures and number of all valid secondary structures, of the
+    // circular molecule if it is one. The matrix is always filled unambiguously for this.
+    pub fn count(&mut self) -> Result<(BigUint, BigUint), ScoreOverflow> {
+        self.matrix = None;
+        let matrix_builder = self
+            .matrix_builder()
+            .with_circular(self.circular)
+            .with_ambiguous(false);
+        let mut matrix = matrix_builder.new_matrix();
+        matrix.fill(&matrix_builder)?;
+
+        let counts = StructureCounter::new(&matrix_builder).count(&matrix);
+        self.matrix = Some(matrix);
+        Ok(counts)
+    }
+
+    // Every distinct structure scoring within `delta` of the optimum, best first, at most `limit`.
+    pub fn suboptimal(
+        &mut self,
+        delta: usize,
+        limit: usize,
+    ) -> Result<Vec<(String, usize)>, ScoreOverflow> {
+        self.fill(false)?;
+        let matrix_builder = self.matrix_builder();
+        let suboptimal_builder = SuboptimalBuilder::new(&matrix_builder, delta, limit);
+        let path_converter = NussinovPathConverter::new(&self.sequence);
+
+        let structures = suboptimal_builder
+            .build(self.matrix())
+            .iter()
+            .map(|(structure, score)| (path_converter.convert_structure(structure), *score))
+            .collect();
+        Ok(structures)
+    }
+
+    // The `k` best distinct structures with their scores, best first, suboptimal ones included;
+    // `None` if the constraint cannot be satisfied.
+    pub fn top(&mut self, k: usize) -> Result<Option<Vec<(String, usize)>>, ScoreOverflow> {
+        self.fill(false)?;
+        if !self.matrix().root().is_feasible() {
+            return Ok(None);
+        }
+        let matrix_builder = self.matrix_builder();
+        let path_converter = NussinovPathConverter::new(&self.sequence);
+
+        let structures = KBestBuilder::new(&matrix_builder, k)
+            .build(self.matrix())
+            .iter()
+            .map(|(structure, score)| (path_converter.convert_structure(structure), *score))
+            .collect();
+        Ok(Some(structures))
+    }
+
+    // Expects the matrix to be filled by `fill`.
+    fn optimal_structures(&self) -> impl Iterator<Item = String> + '_ {
+        let path_converter = NussinovPathConverter::new(&self.sequence);
+        let matrix_builder = self.matrix_builder().with_circular(self.circular);
+        OptimalStructures::new(self.matrix(), matrix_builder)
+            .map(move |structure| path_converter.convert_structure(&structure))
+    }
+
+    // Why the energy model of `ZukerMatrixBuilder` cannot fold the sequence with the given
+    // options, if it cannot.
+    fn zuker_restriction(&self) -> Option<&'static str> {
+        if self.sequence.strand_break().is_some() {
+            return Some("Zuker folding does not support co-folding two strands");
+        }
+        if self.circular {
+            return Some("Zuker folding does not support circular molecules");
+        }
+        if self.constraint.is_some() {
+            return Some("Zuker folding does not support constraints");
+        }
+        if self.shape.is_some() {
+            return Some("Zuker folding does not support SHAPE data");
+        }
+        if self.max_span.is_some() {
+            return Some("Zuker folding does not support a maximal base-pair span");
+        }
+        None
+    }
+
+    fn zuker_structures(&mut self) -> Result<Vec<String>, ScoreOverflow> {
+        self.matrix = None;
+        let matrix_builder = ZukerMatrixBuilder::new(&self.sequence);
+        let mut matrix = Matrix::new(self.sequence.len());
+        matrix.fill(&matrix_builder)?;
+
+        let traceback_builder = ZukerTracebackPathsBuilder::new(&matrix_builder);
+        let paths = traceback_builder.build(&matrix);
+        let path_converter = NussinovPathConverter::new(&self.sequence);
+        let structures = path_converter.convert(&paths);
+
+        self.matrix = Some(matrix);
+        Ok(structures)
+    }
+
+    fn pseudoknot_structure(&mut self) -> Result<String, ScoreOverflow> {
+        self.matrix = None;
+        let matrix_builder = PseudoknotMatrixBuilder::new(self.matrix_builder());
+        let mut matrix = Matrix::new(self.sequence.len());
+        matrix.fill(&matrix_builder)?;
+
+        let structure = matrix_builder.structure(&matrix);
+        let path_converter = NussinovPathConverter::new(&self.sequence);
+        let structure = path_converter.convert_structure(&structure);
+
+        self.matrix = Some(matrix);
+        Ok(structure)
+    }
+
+    fn matrix_builder(&self) -> NussinovMatrixBuilder<'_> {
+        let mut matrix_builder =
+            NussinovMatrixBuilder::new(&self.sequence, self.minimal_loop_length)
+                .with_scoring(self.scoring.as_ref())
+                .with_max_span(self.max_span)
+                .with_ambiguous(self.ambiguous);
+        if let Some(constraint) = &self.constraint {
+            matrix_builder = matrix_builder.with_constraint(constraint);
+        }
+        if let Some(shape) = &self.shape {
+            matrix_builder = matrix_builder.with_shape(shape);
+        }
+        matrix_builder
+    }
+
+    // Expects one of the methods above to have filled the matrix.
+    fn matrix(&self) -> &Matrix {
+        self.matrix
+            .as_ref()
+            .expect("The matrix has not been filled")
+    }
+
+    fn fill(&mut self, circular: bool) -> Result<(), ScoreOverflow> {
+        self.matrix = None;
+        let matrix_builder = self.matrix_builder().with_circular(circular);
+        let mut matrix = matrix_builder.new_matrix();
+        matrix.fill(&matrix_builder)?;
+        self.matrix = Some(matrix);
+        Ok(())
+    }
+}
+
+// Prints the structures as they are traced, in the layout of a pretty-printed vector.
+fn print_structures(structures: impl Iterator<Item = String>) {
+    println!("[");
+    for structure in structures {
+        println!("    {:?},", structure);
+    }
+    println!("]");
+}
+
+impl RNASequence {
+    const VALID_CHARS: [char; 4] = ['A', 'U', 'G', 'C'];
+
+    const STRAND_BREAK: char = '&';
+
+    // Two strands separated by `&` are folded together as a dimer.
+    pub fn new(rna_sequence: &str) -> Result<RNASequence, InvalidSequence> {
+        let sequence = rna_sequence.to_uppercase();
+        let strands: Vec<&str> = sequence.split(RNASequence::STRAND_BREAK).collect();
+        if strands.len() > 2 || !strands.iter().all(|s| RNASequence::is_valid(s)) {
+            return Err(InvalidSequence);
+        }
+
+        let strand_break = match strands[..] {
+            [first, second] if !first.is_empty() && !second.is_empty() => Some(first.len()),
+            [_, _] => return Err(InvalidSequence),
+            _ => None,
+        };
+
+        Ok(RNASequence {
+            nucleotides: strands.concat().chars().collect(),
+            strand_break,
+        })
+    }
+
+    const GAP: char = '-';
+
+    // A row of a multiple sequence alignment: gaps (`-`, `.`, `_` or `~`) are kept as `-` and DNA
+    // is transcribed.
+    pub fn from_alignment_row(row: &str) -> Result<RNASequence, InvalidSequence> {
+        let nucleotides = row
+            .to_uppercase()
+            .chars()
+            .map(|c| match c {
+                '-' | '.' | '_' | '~' => Ok(RNASequence::GAP),
+                'T' => Ok('U'),
+                c if RNASequence::VALID_CHARS.contains(&c) => Ok(c),
+                _ => Err(InvalidSequence),
+            })
+            .collect::<Result<Vec<char>, InvalidSequence>>()?;
+
+        Ok(RNASequence {
+            nucleotides,
+            strand_break: None,
+        })
+    }
+
+    fn is_valid(sequence: &str) -> bool {
+        sequence
+            .chars()
+            .all(|c| RNASequence::VALID_CHARS.contains(&c))
+    }
+
+    pub fn len(&self) -> usize {
+        self.nucleotides.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.nucleotides.is_empty()
+    }
+
+    pub fn is_gap(&self, i: usize) -> bool {
+        self.nucleotides[i] == RNASequence::GAP
+    }
+
+    // Index of the first nucleotide of the second strand.
+    pub fn strand_break(&self) -> Option<usize> {
+        self.strand_break
+    }
+
+    pub fn is_intermolecular(&self, i: usize, j: usize) -> bool {
+        match self.strand_break {
+            Some(b) => i < b && b <= j,
+            None => false,
+        }
+    }
+}
+
+// Single strands print as before dimers were supported; a dimer adds the index of its break.
+impl fmt::Debug for RNASequence {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut tuple = f.debug_tuple("RNASequence");
+        tuple.field(&self.nucleotides);
+        if let Some(strand_break) = self.strand_break {
+            tuple.field(&strand_break);
+        }
+        tuple.finish()
+    }
+}
+
+impl Index<usize> for &RNASequence {
+    type Output = char;
+
+    fn index(&self, index: usize) -> &Self::Output {
+        &self.nucleotides[index]
+    }
+}
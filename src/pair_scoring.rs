@@ -0,0 +1,99 @@
+use std::collections::HashMap;
+
+use crate::nussinov::RNASequence;
+
+pub trait PairScoring {
+    fn score(&self, first: char, second: char) -> Option<usize>;
+
+    // Score of pairing the positions `i` and `j` of `sequence`. Scorings that look beyond the two
+    // nucleotides, like the covariation in an alignment, override this.
+    fn score_at(&self, sequence: &RNASequence, i: usize, j: usize) -> Option<usize> {
+        self.score(sequence[i], sequence[j])
+    }
+}
+
+pub struct UnitScoring;
+
+pub struct WeightedScoring;
+
+pub struct TableScoring(HashMap<(char, char), usize>);
+
+const CANONICAL_PAIRS: [[char; 2]; 3] = [['A', 'U'], ['G', 'C'], ['G', 'U']];
+
+fn is_canonical(first: char, second: char) -> bool {
+    CANONICAL_PAIRS
+        .iter()
+        .any(|c| c.contains(&first) && c.contains(&second) && first != second)
+}
+
+impl PairScoring for UnitScoring {
+    fn score(&self, first: char, second: char) -> Option<usize> {
+        if is_canonical(first, second) {
+            return Some(1);
+        }
+        None
+    }
+}
+
+impl PairScoring for WeightedScoring {
+    fn score(&self, first: char, second: char) -> Option<usize> {
+        match (first, second) {
+            ('G', 'C') | ('C', 'G') => Some(3),
+            ('A', 'U') | ('U', 'A') => Some(2),
+            ('G', 'U') | ('U', 'G') => Some(1),
+            _ => None,
+        }
+    }
+}
+
+impl TableScoring {
+    pub fn new(table: HashMap<(char, char), usize>) -> TableScoring {
+        TableScoring(table)
+    }
+
+    // One pair per line, e.g. `G C 3`. Empty lines and lines starting with `#` are skipped.
+    pub fn from_file(path: &str) -> Result<TableScoring, String> {
+        let content = std::fs::read_to_string(path).map_err(|e| e.to_string())?;
+        let mut table = HashMap::new();
+
+        for (number, line) in content.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            let entry = match fields[..] {
+                [first, second, score] => {
+                    let first = first.to_uppercase().chars().next();
+                    let second = second.to_uppercase().chars().next();
+                    (first, second, score.parse::<usize>().ok())
+                }
+                _ => (None, None, None),
+            };
+
+            match entry {
+                (Some(first), Some(second), Some(score)) => {
+                    table.insert((first, second), score);
+                }
+                _ => {
+                    return Err(format!(
+                        "Invalid scoring table entry in line {}",
+                        number + 1
+                    ))
+                }
+            }
+        }
+
+        Ok(TableScoring(table))
+    }
+}
+
+impl PairScoring for TableScoring {
+    fn score(&self, first: char, second: char) -> Option<usize> {
+        self.0
+            .get(&(first, second))
+            .or_else(|| self.0.get(&(second, first)))
+            .copied()
+    }
+}
@@ -1,76 +1,49 @@
-use crate::{
-    matrix::Position,
-    nussinov::RNASequence,
-    traceback_paths::TracebackPaths,
-    traceback_paths::{TracebackPath, TracebackPathElement},
-};
+use crate::{nussinov::RNASequence, structure::Structure, traceback_paths::TracebackPaths};
 
-pub type SymbolicPaths = Vec<&'static str>;
+pub type SymbolicPaths = Vec<String>;
 
 pub trait PathConverter {
     fn convert(&self, paths: &TracebackPaths) -> SymbolicPaths;
+
+    fn convert_structure(&self, structure: &Structure) -> String;
 }
 
 pub struct NussinovPathConverter<'s>(&'s RNASequence);
 
 impl NussinovPathConverter<'_> {
-    pub fn new<'a>(sequence: &'a RNASequence) -> NussinovPathConverter {
+    pub fn new<'a>(sequence: &'a RNASequence) -> NussinovPathConverter<'a> {
         NussinovPathConverter::<'a>(sequence)
     }
 }
 
 impl PathConverter for NussinovPathConverter<'_> {
     fn convert(&self, paths: &TracebackPaths) -> SymbolicPaths {
-        let mut symbolic_paths = vec![];
-        for path in paths {
-            let mut char_string = vec!['.'; self.0.len()];
-
-            let flatten_path: Vec<Position> = NussinovPathConverter::flatten_path(path);
-
-            for pos in flatten_path.windows(2).into_iter() {
-                let first_pos = pos[0];
-                let second_pos = pos[1];
-                let diagonal = first_pos.is_diagonal_relation(&second_pos);
-
-                if diagonal {
-                    let opening_index = first_pos.i;
-                    let closing_index = second_pos.j;
-
-                    let _ = std::mem::replace(&mut char_string[opening_index], '(');
-                    let _ = std::mem::replace(&mut char_string[closing_index], ')');
-                }
-            }
-
-            let string: String = char_string.into_iter().collect();
-            symbolic_paths.push(string)
-        }
-
-        symbolic_paths
-            .into_iter()
-            .map(|s| NussinovPathConverter::leak_string(s))
+        paths
+            .iter()
+            .map(|path| self.convert_structure(&Structure::from_path(path, self.0.len())))
             .collect()
     }
-}
 
-impl NussinovPathConverter<'_> {
-    fn leak_string(s: String) -> &'static str {
-        Box::leak(s.into_boxed_str())
-    }
+    fn convert_structure(&self, structure: &Structure) -> String {
+        let mut char_string = vec!['.'; self.0.len()];
 
-    fn flatten_path(path: &TracebackPath) -> Vec<Position> {
-        let mut position_path = vec![];
-
-        for path_element in path {
-            match path_element {
-                TracebackPathElement::Single(p) => position_path.push(*p),
-                TracebackPathElement::Decomposition(f, s) => {
-                    let mut decomp_path = NussinovPathConverter::flatten_path(f);
-                    decomp_path.append(&mut NussinovPathConverter::flatten_path(s));
-                    position_path.append(&mut decomp_path)
+        // Crossing pairs get the brackets of higher levels, and letters beyond those.
+        for ((opening_index, closing_index), level) in structure.levels() {
+            let (open, close) = match Structure::BRACKETS.get(level) {
+                Some(brackets) => *brackets,
+                None => {
+                    let letter = (b'A' + (level - Structure::BRACKETS.len()) as u8) as char;
+                    (letter, letter.to_ascii_lowercase())
                 }
-            }
+            };
+            let _ = std::mem::replace(&mut char_string[opening_index], open);
+            let _ = std::mem::replace(&mut char_string[closing_index], close);
+        }
+
+        if let Some(strand_break) = self.0.strand_break() {
+            char_string.insert(strand_break, '&');
         }
 
-        position_path
+        char_string.into_iter().collect()
     }
 }
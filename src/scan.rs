@@ -0,0 +1,302 @@
+use std::collections::{BTreeSet, VecDeque};
+
+use crate::{
+    matrix::{Position, TraceType},
+    matrix_builder::NussinovMatrixBuilder,
+    nussinov::{InvalidSequence, RNASequence},
+    pair_scoring::{PairScoring, UnitScoring},
+};
+
+// Folds sequences of arbitrary length with a sliding window, in the spirit of RNALfold. Nucleotides
+// are pushed one at a time and only pairs spanning at most `window` nucleotides are allowed. Every
+// cell of the banded matrix is computed once and shared by all windows containing it, so memory
+// stays bounded by the window instead of the sequence length. The cells follow the recursion of
+// `NussinovMatrixBuilder::rules` on the nucleotides of the current window, so the scan computes
+// the same band as a fold with a maximal span without holding the whole sequence in memory.
+//
+// The reported structures are the exterior components of the optimal structure of the whole
+// sequence. A component is held back until every traceback of a longer prefix has to pass it,
+// so structures that a later, larger one would contain are never reported.
+pub struct WindowScanner {
+    window: usize,
+    minimal_loop_length: usize,
+    scoring: Box<dyn PairScoring>,
+    len: usize,
+    nucleotides: VecDeque<char>,
+    // Scores of the half-open cells (j - gap, j), one column per end position j, indexed by gap.
+    columns: VecDeque<Vec<usize>>,
+    // Best score of every prefix of the sequence, one per end position j.
+    prefixes: VecDeque<usize>,
+    // End position j of the oldest column still kept.
+    first_column: usize,
+    // Traceback of every prefix j since the last reported one: the shorter prefix it extends and
+    // the structure it appends, if any.
+    tracebacks: VecDeque<(usize, Option<LocalStructure>)>,
+    // Prefix up to which every structure has been reported.
+    reported: usize,
+}
+
+// A locally stable structure closed by a pair between `start` and `end` (1-based, inclusive).
+#[derive(Debug, Clone)]
+pub struct LocalStructure {
+    pub start: usize,
+    pub end: usize,
+    pub score: usize,
+    pub structure: String,
+}
+
+impl WindowScanner {
+    pub fn new(window: usize, minimal_loop_length: usize) -> Result<WindowScanner, String> {
+        if window == 0 {
+            return Err("The scanning window has to span at least one nucleotide".to_string());
+        }
+        Ok(WindowScanner {
+            window,
+            minimal_loop_length,
+            scoring: Box::new(UnitScoring),
+            len: 0,
+            nucleotides: VecDeque::new(),
+            columns: VecDeque::from(vec![vec![0]]),
+            prefixes: VecDeque::from(vec![0]),
+            first_column: 0,
+            tracebacks: VecDeque::from(vec![(0, None)]),
+            reported: 0,
+        })
+    }
+
+    pub fn with_scoring(mut self, scoring: Box<dyn PairScoring>) -> WindowScanner {
+        self.scoring = scoring;
+        self
+    }
+
+    // Number of nucleotides scanned so far.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    // Appends the next nucleotide (DNA input is transcribed) and fills the new matrix column.
+    // Returns the structures that no nucleotide still to come can change, from 5' to 3'.
+    pub fn push(&mut self, nucleotide: char) -> Result<Vec<LocalStructure>, InvalidSequence> {
+        let nucleotide = match nucleotide.to_ascii_uppercase() {
+            'T' => 'U',
+            n @ ('A' | 'U' | 'G' | 'C') => n,
+            _ => return Err(InvalidSequence),
+        };
+        self.nucleotides.push_back(nucleotide);
+        if self.nucleotides.len() > self.window {
+            self.nucleotides.pop_front();
+        }
+        self.len += 1;
+
+        // The builder sees the current window only: its position 0 is the nucleotide `first`.
+        let j = self.len;
+        let first = j - self.nucleotides.len();
+        let sequence = RNASequence::new(&self.nucleotides.iter().collect::<String>())?;
+        let matrix_builder = NussinovMatrixBuilder::new(&sequence, self.minimal_loop_length)
+            .with_scoring(self.scoring.as_ref())
+            .with_max_span(Some(self.window - 1));
+
+        let mut column = vec![0; j - first + 1];
+        for i in first..j {
+            let cell = Position::from(i - first, j - first);
+            column[j - i] = matrix_builder
+                .rules(cell)
+                .map(|(trace_type, score)| self.rule_value(first, &trace_type, score))
+                .max()
+                .unwrap_or(0);
+        }
+
+        // Prefixes extend beyond the window: the rules of its first cell are applied to the
+        // prefix ending where the window starts instead.
+        let mut prefix = self.prefix(j - 1);
+        let mut best = None;
+        for (trace_type, score) in matrix_builder.rules(Position::from(0, j - first)) {
+            if let TraceType::Closed(outer, inner) = trace_type {
+                let k = outer.j + first;
+                let closed = score + self.value(inner.i + first, inner.j + first);
+                if self.prefix(k) + closed > prefix {
+                    prefix = self.prefix(k) + closed;
+                    best = Some((k, closed));
+                }
+            }
+        }
+
+        let traceback = match best {
+            Some((k, score)) => {
+                let local = LocalStructure {
+                    start: k + 1,
+                    end: j,
+                    score,
+                    structure: self.traceback(&matrix_builder, first, k, j - 1),
+                };
+                (k, Some(local))
+            }
+            None => (j - 1, None),
+        };
+        self.tracebacks.push_back(traceback);
+
+        self.columns.push_back(column);
+        self.prefixes.push_back(prefix);
+        if self.columns.len() > self.window {
+            self.columns.pop_front();
+            self.prefixes.pop_front();
+            self.first_column += 1;
+        }
+
+        // Every later prefix extends one of these, so all their tracebacks meet before them.
+        let mut prefixes: BTreeSet<usize> = ((j + 1).saturating_sub(self.window)..=j)
+            .filter(|prefix| *prefix >= self.reported)
+            .collect();
+        while prefixes.len() > 1 {
+            let last = prefixes.pop_last().unwrap();
+            prefixes.insert(self.tracebacks[last - self.reported].0);
+        }
+        Ok(self.report(prefixes.pop_last().unwrap()))
+    }
+
+    // The structures that are still held back, to be called once the input ends.
+    pub fn finish(&mut self) -> Vec<LocalStructure> {
+        self.report(self.len)
+    }
+
+    // Reports the structures on the traceback of `prefix` that have not been reported yet.
+    fn report(&mut self, prefix: usize) -> Vec<LocalStructure> {
+        let mut structures = vec![];
+        let mut current = prefix;
+        while current > self.reported {
+            let (previous, local) = &mut self.tracebacks[current - self.reported];
+            structures.extend(local.take());
+            current = *previous;
+        }
+
+        self.tracebacks.drain(..(prefix - self.reported));
+        self.reported = prefix;
+        structures.reverse();
+        structures
+    }
+
+    // Best score of the sequence scanned so far, using only pairs within the window.
+    pub fn score(&self) -> usize {
+        self.prefix(self.len)
+    }
+
+    fn value(&self, i: usize, j: usize) -> usize {
+        self.columns[j - self.first_column][j - i]
+    }
+
+    fn prefix(&self, j: usize) -> usize {
+        self.prefixes[j - self.first_column]
+    }
+
+    // Value of a rule of a window starting at the nucleotide `first`.
+    fn rule_value(&self, first: usize, trace_type: &TraceType, score: usize) -> usize {
+        let value = |pos: &Position| self.value(pos.i + first, pos.j + first);
+        match trace_type {
+            TraceType::Unpaired(pos) => value(pos),
+            TraceType::Closed(prefix, inner) => value(prefix) + score + value(inner),
+            _ => unreachable!("`rules` only derives unpaired and closed cells"),
+        }
+    }
+
+    // Dot-bracket notation of an optimal structure closed by the pair (`start`, `end`), both
+    // within the window of `matrix_builder` starting at the nucleotide `first`.
+    fn traceback(
+        &self,
+        matrix_builder: &NussinovMatrixBuilder,
+        first: usize,
+        start: usize,
+        end: usize,
+    ) -> String {
+        let mut structure = vec!['.'; end - start + 1];
+        let mut cells = vec![Position::from(start + 1, end)];
+        structure[0] = '(';
+        structure[end - start] = ')';
+
+        while let Some(cell) = cells.pop() {
+            if cell.i == cell.j {
+                continue;
+            }
+            let value = self.value(cell.i, cell.j);
+            let (trace_type, _) = matrix_builder
+                .rules(Position::from(cell.i - first, cell.j - first))
+                .find(|(trace_type, score)| self.rule_value(first, trace_type, *score) == value)
+                .unwrap();
+            match trace_type {
+                TraceType::Unpaired(pos) => {
+                    cells.push(Position::from(pos.i + first, pos.j + first))
+                }
+                TraceType::Closed(prefix, inner) => {
+                    structure[prefix.j + first - start] = '(';
+                    structure[inner.j + first - start] = ')';
+                    cells.push(Position::from(prefix.i + first, prefix.j + first));
+                    cells.push(Position::from(inner.i + first, inner.j + first));
+                }
+                _ => unreachable!("`rules` only derives unpaired and closed cells"),
+            }
+        }
+
+        structure.into_iter().collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{LocalStructure, WindowScanner};
+    use crate::{
+        matrix_builder::{MatrixBuilder, NussinovMatrixBuilder},
+        nussinov::RNASequence,
+        pair_scoring::WeightedScoring,
+    };
+
+    fn scan(sequence: &str, window: usize) -> Vec<(String, usize, usize)> {
+        let mut scanner = WindowScanner::new(window, 1).unwrap();
+        let mut structures: Vec<LocalStructure> = vec![];
+        for nucleotide in sequence.chars() {
+            structures.extend(scanner.push(nucleotide).unwrap());
+        }
+        structures.extend(scanner.finish());
+        structures
+            .into_iter()
+            .map(|local| (local.structure, local.start, local.end))
+            .collect()
+    }
+
+    #[test]
+    fn reports_only_maximal_structures() {
+        let expected = vec![
+            ("(((...)))".to_string(), 1, 9),
+            ("(((...)))".to_string(), 14, 22),
+        ];
+        assert_eq!(scan("GGGAAACCCAAAAGGGAAACCC", 20), expected);
+    }
+
+    #[test]
+    fn scores_like_a_fold_with_a_maximal_span() {
+        let sequence = "GGGAAACCCAUGCAUUAGCGCUAAGCUGGAUCCAGCUUAGCGCUAAUGCAUGGG";
+        let rna_sequence = RNASequence::new(sequence).unwrap();
+        for window in [1, 2, 5, 9, 20, sequence.len()] {
+            let mut scanner = WindowScanner::new(window, 3)
+                .unwrap()
+                .with_scoring(Box::new(WeightedScoring));
+            sequence.chars().for_each(|n| {
+                scanner.push(n).unwrap();
+            });
+
+            let matrix_builder = NussinovMatrixBuilder::new(&rna_sequence, 3)
+                .with_scoring(&WeightedScoring)
+                .with_max_span(Some(window - 1));
+            let mut matrix = matrix_builder.new_matrix();
+            matrix_builder.fill(&mut matrix).unwrap();
+            assert_eq!(scanner.score(), matrix.root().value(), "window {}", window);
+        }
+    }
+
+    #[test]
+    fn rejects_an_empty_window() {
+        assert!(WindowScanner::new(0, 1).is_err());
+    }
+}
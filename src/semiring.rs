@@ -0,0 +1,178 @@
+use num_bigint::BigUint;
+
+// Evaluates the structures of a sequence by combining alternative structures with `add` and the
+// parts of one structure with `mul`. See `NussinovMatrixBuilder::inside` for the recursion.
+pub trait Semiring {
+    type Value: Clone;
+
+    // Value of no structure at all.
+    fn zero(&self) -> Self::Value;
+
+    // Value of the empty structure.
+    fn one(&self) -> Self::Value;
+
+    fn add(&self, a: &Self::Value, b: &Self::Value) -> Self::Value;
+
+    fn mul(&self, a: &Self::Value, b: &Self::Value) -> Self::Value;
+
+    // Value of a base pair with the given score.
+    fn pair(&self, score: usize) -> Self::Value;
+
+    // Value of an unpaired nucleotide.
+    fn unpaired(&self) -> Self::Value {
+        self.one()
+    }
+}
+
+// Best score of all structures; `None` if there is none. This is how matrices are filled.
+pub struct MaxPlus;
+
+// Lowest energy of all structures, with every pair contributing minus its score.
+pub struct MinPlus;
+
+// Number of structures.
+pub struct Counting;
+
+// Partition function: the sum of the Boltzmann weights exp(score / kT) of all structures. Every
+// nucleotide is divided by `scale` to keep long sequences from overflowing.
+pub struct SumProduct {
+    pub kt: f64,
+    pub scale: f64,
+}
+
+impl Semiring for MaxPlus {
+    type Value = Option<usize>;
+
+    fn zero(&self) -> Self::Value {
+        None
+    }
+
+    fn one(&self) -> Self::Value {
+        Some(0)
+    }
+
+    fn add(&self, a: &Self::Value, b: &Self::Value) -> Self::Value {
+        (*a).max(*b)
+    }
+
+    fn mul(&self, a: &Self::Value, b: &Self::Value) -> Self::Value {
+        Some((*a)? + (*b)?)
+    }
+
+    fn pair(&self, score: usize) -> Self::Value {
+        Some(score)
+    }
+}
+
+impl Semiring for MinPlus {
+    type Value = Option<i64>;
+
+    fn zero(&self) -> Self::Value {
+        None
+    }
+
+    fn one(&self) -> Self::Value {
+        Some(0)
+    }
+
+    fn add(&self, a: &Self::Value, b: &Self::Value) -> Self::Value {
+        match (a, b) {
+            (Some(a), Some(b)) => Some(*a.min(b)),
+            _ => a.or(*b),
+        }
+    }
+
+    fn mul(&self, a: &Self::Value, b: &Self::Value) -> Self::Value {
+        Some((*a)? + (*b)?)
+    }
+
+    fn pair(&self, score: usize) -> Self::Value {
+        Some(-(score as i64))
+    }
+}
+
+impl Semiring for Counting {
+    type Value = BigUint;
+
+    fn zero(&self) -> Self::Value {
+        BigUint::default()
+    }
+
+    fn one(&self) -> Self::Value {
+        BigUint::from(1u8)
+    }
+
+    fn add(&self, a: &Self::Value, b: &Self::Value) -> Self::Value {
+        a + b
+    }
+
+    fn mul(&self, a: &Self::Value, b: &Self::Value) -> Self::Value {
+        a * b
+    }
+
+    fn pair(&self, _score: usize) -> Self::Value {
+        self.one()
+    }
+}
+
+impl Semiring for SumProduct {
+    type Value = f64;
+
+    fn zero(&self) -> Self::Value {
+        0.0
+    }
+
+    fn one(&self) -> Self::Value {
+        1.0
+    }
+
+    fn add(&self, a: &Self::Value, b: &Self::Value) -> Self::Value {
+        a + b
+    }
+
+    fn mul(&self, a: &Self::Value, b: &Self::Value) -> Self::Value {
+        a * b
+    }
+
+    fn pair(&self, score: usize) -> Self::Value {
+        (score as f64 / self.kt).exp() / (self.scale * self.scale)
+    }
+
+    fn unpaired(&self) -> Self::Value {
+        1.0 / self.scale
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        brute_force, constraint::Constraint, matrix_builder::NussinovMatrixBuilder,
+        nussinov::RNASequence,
+    };
+
+    use super::{MaxPlus, MinPlus};
+
+    #[test]
+    fn min_plus_finds_the_lowest_energy() {
+        for sequence in brute_force::SEQUENCES {
+            let sequence = RNASequence::new(sequence).unwrap();
+            let n = sequence.len();
+            for matrix_builder in brute_force::builders(&sequence) {
+                let structures = brute_force::structures(&matrix_builder, n);
+                let energy = structures.iter().map(|(_, score)| -(*score as i64)).min();
+                assert_eq!(matrix_builder.inside(&MinPlus)[(0, n)], energy);
+
+                let score = matrix_builder.inside(&MaxPlus)[(0, n)];
+                assert_eq!(energy, score.map(|score| -(score as i64)));
+            }
+        }
+    }
+
+    #[test]
+    fn min_plus_has_no_energy_without_structures() {
+        let sequence = RNASequence::new("GGAAA").unwrap();
+        let constraint = Constraint::new("<....").unwrap();
+        let matrix_builder = NussinovMatrixBuilder::new(&sequence, 1).with_constraint(&constraint);
+        assert_eq!(matrix_builder.inside(&MinPlus)[(0, 5)], None);
+    }
+}
@@ -1,14 +1,211 @@
-use clap::Parser;
+use clap::{Args, Parser, Subcommand};
+
+use crate::nussinov::Algorithm;
 
 #[derive(Parser)]
 #[clap(name = "Nussinov RNA Algorithm")]
 #[clap(author = "Leon Fuss <hello@leonfuss.me")]
 pub struct Settings {
+    #[clap(subcommand)]
+    pub command: Option<Command>,
+
     #[clap(short, long, value_parser = file_exists)]
     pub file: Option<String>,
 
+    /// Stockholm or Clustal alignment whose sequences are folded into a consensus structure
+    #[clap(
+        long,
+        value_parser = file_exists,
+        conflicts_with_all = &["algorithm", "window", "circular", "ambiguous", "shape", "probabilities", "mea", "centroid", "count", "grammar", "sample", "random-optimal", "suboptimal", "top"]
+    )]
+    pub alignment: Option<String>,
+
+    /// RNA sequence; separate two strands with `&` to fold them as a dimer
     #[clap(short, long, value_parser)]
     pub sequence: Option<String>,
+
+    /// Pair scoring model: `unit`, `weighted` (GC=3, AU=2, GU=1), a path to a scoring table or
+    /// a parameter file written by `train`
+    #[clap(long, value_parser = scoring_exists, default_value = "unit", global = true)]
+    pub scoring: String,
+
+    #[clap(long, value_enum, default_value = "nussinov")]
+    pub algorithm: Algorithm,
+
+    /// Never pair nucleotides that are more than L positions apart
+    #[clap(long, value_name = "L")]
+    pub max_span: Option<usize>,
+
+    /// Scan the input with a sliding window of W nucleotides and report locally stable structures
+    #[clap(
+        long,
+        value_name = "W",
+        value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..),
+        conflicts_with_all = &["algorithm", "max-span", "circular", "ambiguous", "constraint", "shape", "probabilities", "mea", "centroid", "count", "grammar", "sample", "random-optimal", "suboptimal", "top"]
+    )]
+    pub window: Option<usize>,
+
+    /// Print at most N optimal structures
+    #[clap(long, value_name = "N", global = true)]
+    pub max_structures: Option<usize>,
+
+    /// Fold the sequence as a circular molecule
+    #[clap(long)]
+    pub circular: bool,
+
+    /// Use the textbook recursion, which prints a structure once for each of its derivations
+    #[clap(long)]
+    pub ambiguous: bool,
+
+    /// Hard constraint: `x` unpaired, `(`/`)` forced pair, `<`/`>` paired downstream/upstream, `.` free
+    #[clap(long)]
+    pub constraint: Option<String>,
+
+    /// SHAPE reactivities, one `position reactivity` pair per line. Pair scores, including those
+    /// printed and the DELTA of --suboptimal, are then counted in hundredths
+    #[clap(long, value_parser = file_exists)]
+    pub shape: Option<String>,
+
+    /// Slope m of the SHAPE pseudo-score m * ln(r + 1) + b
+    #[clap(long, default_value = "1.8")]
+    pub shape_slope: f64,
+
+    /// Intercept b of the SHAPE pseudo-score m * ln(r + 1) + b
+    #[clap(long, default_value = "-0.6", allow_hyphen_values = true)]
+    pub shape_intercept: f64,
+
+    /// Print the ensemble free energy and the base-pair probability matrix of the linear sequence
+    #[clap(long, conflicts_with = "circular")]
+    pub probabilities: bool,
+
+    /// Print the maximum expected accuracy structure; GAMMA weighs pairs against unpaired bases
+    #[clap(
+        long,
+        value_name = "GAMMA",
+        conflicts_with = "circular",
+        min_values = 0,
+        default_missing_value = "1.0"
+    )]
+    pub mea: Option<f64>,
+
+    /// Print the centroid structure of the Boltzmann ensemble
+    #[clap(long, conflicts_with = "circular")]
+    pub centroid: bool,
+
+    /// Count the optimal structures and all valid secondary structures without enumerating them
+    #[clap(long)]
+    pub count: bool,
+
+    /// Folding grammar such as `S -> aSb | aS | Sa | SS | ε`, or a file containing one; see `--algebra`
+    #[clap(long, conflicts_with = "circular")]
+    pub grammar: Option<String>,
+
+    /// Algebra evaluating `--grammar`: `score`, `count`, `structure`, `shape` or a product such as
+    /// `score*count`, whose first algebra may not be `count`
+    #[clap(long, default_value = "score*count")]
+    pub algebra: String,
+
+    /// Draw N structures from the Boltzmann ensemble
+    #[clap(long, value_name = "N", conflicts_with = "circular")]
+    pub sample: Option<usize>,
+
+    /// Print one optimal structure drawn uniformly from all distinct optimal structures
+    #[clap(long)]
+    pub random_optimal: bool,
+
+    /// Seed for the random number generator
+    #[clap(long)]
+    pub seed: Option<u64>,
+
+    /// Enumerate all structures scoring within DELTA of the optimum of the linear sequence
+    #[clap(long, value_name = "DELTA", conflicts_with = "circular")]
+    pub suboptimal: Option<usize>,
+
+    /// Report the K best distinct structures of the linear sequence, suboptimal ones included,
+    /// best first
+    #[clap(long, value_name = "K", conflicts_with_all = &["suboptimal", "circular"])]
+    pub top: Option<usize>,
+
+    /// Maximum number of suboptimal structures to report
+    #[clap(long, default_value = "100")]
+    pub limit: usize,
+}
+
+#[derive(Subcommand)]
+pub enum Command {
+    /// Search for a sequence whose optimal structure is the given target
+    Design(DesignSettings),
+    /// Compare predicted structures with a reference structure
+    Eval(EvalSettings),
+    /// Learn grammar parameters from sequences with or without known structures
+    Train(TrainSettings),
+}
+
+#[derive(Args)]
+pub struct DesignSettings {
+    /// Target structure in dot-bracket notation
+    #[clap(long)]
+    pub structure: String,
+
+    /// Seed for the random number generator
+    #[clap(long)]
+    pub seed: Option<u64>,
+
+    /// Minimal fraction of G and C nucleotides
+    #[clap(long, default_value = "0.0")]
+    pub min_gc: f64,
+
+    /// Maximal fraction of G and C nucleotides
+    #[clap(long, default_value = "1.0")]
+    pub max_gc: f64,
+
+    /// Motif that must not occur in the designed sequence; may be given several times
+    #[clap(long = "forbid", value_name = "MOTIF")]
+    pub forbidden_motifs: Vec<String>,
+
+    /// Require the target to be the only optimal structure
+    #[clap(long)]
+    pub unique: bool,
+
+    /// Maximal number of mutations to try
+    #[clap(long, default_value = "10000")]
+    pub iterations: usize,
+}
+
+#[derive(Args)]
+pub struct EvalSettings {
+    /// Reference structure in dot-bracket notation; `[]{}<>` mark pseudoknotted pairs
+    #[clap(long)]
+    pub reference: String,
+
+    /// Predicted structure to compare; may be given several times. Without it, the optimal
+    /// structures of the given sequence are compared
+    #[clap(long)]
+    pub predicted: Vec<String>,
+
+    /// Also count pairs shifted by one nucleotide on either side as correct
+    #[clap(long)]
+    pub slip: bool,
+}
+
+#[derive(Args)]
+pub struct TrainSettings {
+    /// FASTA file of training sequences; a dot-bracket line after a sequence gives its known
+    /// structure
+    #[clap(long, value_parser = file_exists)]
+    pub input: String,
+
+    /// File the parameters are written to; pass it to --scoring to fold with them
+    #[clap(long)]
+    pub output: String,
+
+    /// Maximal number of expectation maximization iterations
+    #[clap(long, default_value = "100")]
+    pub iterations: usize,
+
+    /// Stop once the log-likelihood improves by less than this
+    #[clap(long, default_value = "0.0001")]
+    pub tolerance: f64,
 }
 
 fn file_exists(s: &str) -> Result<String, String> {
@@ -18,3 +215,11 @@ fn file_exists(s: &str) -> Result<String, String> {
 
     Err("The provided file path doesn't exist".into())
 }
+
+fn scoring_exists(s: &str) -> Result<String, String> {
+    if s == "unit" || s == "weighted" {
+        return Ok(s.into());
+    }
+
+    file_exists(s)
+}
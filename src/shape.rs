@@ -0,0 +1,112 @@
+pub struct ShapeData {
+    reactivities: Vec<Option<f64>>,
+    slope: f64,
+    intercept: f64,
+}
+
+impl ShapeData {
+    pub const DEFAULT_SLOPE: f64 = 1.8;
+    pub const DEFAULT_INTERCEPT: f64 = -0.6;
+    // Pair scores are counted in hundredths while SHAPE data applies, so that pseudo-scores
+    // below one reweight pairs instead of being rounded away or cancelling them.
+    pub const SCALE: usize = 100;
+
+    pub fn new(reactivities: Vec<Option<f64>>) -> ShapeData {
+        ShapeData {
+            reactivities,
+            slope: ShapeData::DEFAULT_SLOPE,
+            intercept: ShapeData::DEFAULT_INTERCEPT,
+        }
+    }
+
+    // Two columns per line: the 1-based position and its reactivity. Negative
+    // reactivities (usually -999) mark positions without data.
+    pub fn from_file(path: &str) -> Result<ShapeData, String> {
+        let content = std::fs::read_to_string(path).map_err(|e| e.to_string())?;
+        let mut reactivities = vec![];
+
+        for (number, line) in content.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            let entry = match fields[..] {
+                [position, reactivity, ..] => (
+                    position.parse::<usize>().ok().filter(|p| *p > 0),
+                    reactivity.parse::<f64>().ok(),
+                ),
+                _ => (None, None),
+            };
+
+            match entry {
+                (Some(position), Some(reactivity)) => {
+                    if reactivities.len() < position {
+                        reactivities.resize(position, None);
+                    }
+                    reactivities[position - 1] = Some(reactivity).filter(|r| *r >= 0.0);
+                }
+                _ => return Err(format!("Invalid SHAPE entry in line {}", number + 1)),
+            }
+        }
+
+        Ok(ShapeData::new(reactivities))
+    }
+
+    pub fn with_parameters(mut self, slope: f64, intercept: f64) -> ShapeData {
+        self.slope = slope;
+        self.intercept = intercept;
+        self
+    }
+
+    pub fn len(&self) -> usize {
+        self.reactivities.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.reactivities.is_empty()
+    }
+
+    // Pseudo-score for pairing nucleotide `i`, following Deigan et al.: m * ln(r + 1) + b.
+    // Positive values penalise pairing, negative values reward it.
+    pub fn pseudo_score(&self, i: usize) -> f64 {
+        match self.reactivities.get(i).copied().flatten() {
+            Some(reactivity) => self.slope * (reactivity + 1.0).ln() + self.intercept,
+            None => 0.0,
+        }
+    }
+
+    // Applies the pseudo-scores of both nucleotides to `score` and returns it in hundredths, see
+    // `SCALE`. Only pairs whose penalty outweighs their whole score are dropped, since they could
+    // never improve a structure.
+    pub fn adjust(&self, score: usize, i: usize, j: usize) -> Option<usize> {
+        let penalty = (self.pseudo_score(i) + self.pseudo_score(j)) * ShapeData::SCALE as f64;
+        let adjusted = (score * ShapeData::SCALE) as f64 - penalty;
+        if adjusted >= 0.5 {
+            return Some(adjusted.round() as usize);
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ShapeData;
+    use crate::nussinov::Nussinov;
+
+    #[test]
+    fn reactivity_reweights_pairs_without_forbidding_them() {
+        // 1.8 ln 2 - 0.6 ≈ 0.65 takes most of a unit pair but leaves it worth forming.
+        let shape = ShapeData::new(vec![Some(1.0)]);
+        assert_eq!(shape.adjust(1, 0, 8), Some(35));
+        let mut nussinov = Nussinov::new("GGGAAACCC", 1).with_shape(shape).unwrap();
+        let structures: Vec<String> = nussinov.structures().unwrap().collect();
+        assert_eq!(structures, vec!["(((...)))".to_string()]);
+
+        // 1.8 ln 6 - 0.6 ≈ 2.63 outweighs the pair.
+        let shape = ShapeData::new(vec![Some(5.0)]);
+        assert_eq!(shape.adjust(1, 0, 8), None);
+        assert_eq!(shape.adjust(3, 0, 8), Some(37));
+    }
+}
@@ -0,0 +1,146 @@
+use crate::{
+    matrix::Position,
+    traceback_paths::{TracebackPath, TracebackPathElement},
+};
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Structure(Vec<Option<usize>>);
+
+impl Structure {
+    pub fn new(len: usize) -> Structure {
+        Structure(vec![None; len])
+    }
+
+    pub fn from_pairs(len: usize, pairs: &[(usize, usize)]) -> Structure {
+        let mut structure = Structure::new(len);
+        for (i, j) in pairs {
+            structure.add_pair(*i, *j);
+        }
+        structure
+    }
+
+    // Bracket types of the successive pseudoknot levels in extended dot-bracket notation.
+    pub const BRACKETS: [(char, char); 4] = [('(', ')'), ('[', ']'), ('{', '}'), ('<', '>')];
+
+    // Parses (extended) dot-bracket notation; `None` if the brackets are unbalanced or unknown
+    // symbols occur.
+    pub fn from_dot_bracket(dot_bracket: &str) -> Option<Structure> {
+        let symbols: Vec<char> = dot_bracket.trim().chars().collect();
+        let mut structure = Structure::new(symbols.len());
+        let mut stacks = vec![vec![]; Structure::BRACKETS.len()];
+
+        for (index, symbol) in symbols.into_iter().enumerate() {
+            if symbol == '.' {
+                continue;
+            }
+            let level = Structure::BRACKETS
+                .iter()
+                .position(|(open, close)| symbol == *open || symbol == *close)?;
+            if symbol == Structure::BRACKETS[level].0 {
+                stacks[level].push(index);
+            } else {
+                structure.add_pair(stacks[level].pop()?, index);
+            }
+        }
+
+        if stacks.iter().any(|stack| !stack.is_empty()) {
+            return None;
+        }
+        Some(structure)
+    }
+
+    pub fn from_path(path: &TracebackPath, len: usize) -> Structure {
+        let mut structure = Structure::new(len);
+        let flatten_path = Structure::flatten_path(path);
+
+        for pos in flatten_path.windows(2) {
+            let first_pos = pos[0];
+            let second_pos = pos[1];
+
+            if first_pos.is_diagonal_relation(&second_pos) {
+                structure.add_pair(first_pos.i, second_pos.j);
+            }
+        }
+
+        structure
+    }
+
+    pub fn add_pair(&mut self, i: usize, j: usize) {
+        self.0[i] = Some(j);
+        self.0[j] = Some(i);
+    }
+
+    pub fn partner(&self, i: usize) -> Option<usize> {
+        self.0[i]
+    }
+
+    pub fn pairs(&self) -> Vec<(usize, usize)> {
+        self.0
+            .iter()
+            .enumerate()
+            .filter_map(|(i, partner)| partner.filter(|j| i < *j).map(|j| (i, j)))
+            .collect()
+    }
+
+    // Base-pair distance: the number of pairs contained in exactly one of both structures.
+    pub fn distance(&self, other: &Structure) -> usize {
+        let pairs = self.pairs();
+        let shared = pairs
+            .iter()
+            .filter(|(i, j)| other.partner(*i) == Some(*j))
+            .count();
+        pairs.len() + other.pairs().len() - 2 * shared
+    }
+
+    // Assigns every pair, from 5' to 3', the lowest level none of whose pairs it crosses.
+    pub fn levels(&self) -> Vec<((usize, usize), usize)> {
+        let mut levels: Vec<Vec<(usize, usize)>> = vec![];
+        let mut assigned = vec![];
+
+        for (i, j) in self.pairs() {
+            let crosses = |(k, l): &(usize, usize)| {
+                (i < *k && *k < j && j < *l) || (*k < i && i < *l && *l < j)
+            };
+            let level = match levels.iter().position(|pairs| !pairs.iter().any(crosses)) {
+                Some(level) => level,
+                None => {
+                    levels.push(vec![]);
+                    levels.len() - 1
+                }
+            };
+            levels[level].push((i, j));
+            assigned.push(((i, j), level));
+        }
+
+        assigned
+    }
+
+    pub fn has_crossing_pairs(&self) -> bool {
+        self.levels().iter().any(|(_, level)| *level > 0)
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    fn flatten_path(path: &TracebackPath) -> Vec<Position> {
+        let mut position_path = vec![];
+
+        for path_element in path {
+            match path_element {
+                TracebackPathElement::Single(p) => position_path.push(*p),
+                TracebackPathElement::Decomposition(f, s) => {
+                    let mut decomp_path = Structure::flatten_path(f);
+                    decomp_path.append(&mut Structure::flatten_path(s));
+                    position_path.append(&mut decomp_path)
+                }
+            }
+        }
+
+        position_path
+    }
+}
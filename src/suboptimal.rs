@@ -0,0 +1,199 @@
+use std::{cmp::Ordering, collections::BinaryHeap};
+
+use crate::{
+    matrix::{Matrix, Position},
+    matrix_builder::NussinovMatrixBuilder,
+    structure::Structure,
+};
+
+pub type SuboptimalStructures = Vec<(Structure, usize)>;
+
+pub struct SuboptimalBuilder<'b, 's> {
+    matrix_builder: &'b NussinovMatrixBuilder<'s>,
+    delta: usize,
+    limit: usize,
+}
+
+// A partially traced structure. `bound` is the best score any completion can reach, so states
+// leave the heap in descending score order once all their intervals are resolved. Among equal
+// bounds the most recent state wins, which keeps the search depth-first.
+struct PartialStructure {
+    bound: usize,
+    order: usize,
+    score: usize,
+    pairs: Vec<(usize, usize)>,
+    intervals: Vec<Position>,
+}
+
+impl<'b, 's> SuboptimalBuilder<'b, 's> {
+    pub fn new(
+        matrix_builder: &'b NussinovMatrixBuilder<'s>,
+        delta: usize,
+        limit: usize,
+    ) -> SuboptimalBuilder<'b, 's> {
+        SuboptimalBuilder {
+            matrix_builder,
+            delta,
+            limit,
+        }
+    }
+
+    pub fn build(&self, matrix: &Matrix) -> SuboptimalStructures {
+        let root = matrix.root();
+        if !root.is_feasible() {
+            return vec![];
+        }
+        let threshold = root.value().saturating_sub(self.delta);
+        let len = matrix.root_position().j;
+
+        let mut intervals = vec![];
+        push_interval(&mut intervals, matrix.root_position());
+
+        let mut heap = BinaryHeap::new();
+        let mut order = 0;
+        heap.push(PartialStructure {
+            bound: root.value(),
+            order,
+            score: 0,
+            pairs: vec![],
+            intervals,
+        });
+
+        let mut structures = vec![];
+        while structures.len() < self.limit {
+            let mut state = match heap.pop() {
+                Some(state) => state,
+                None => break,
+            };
+            let interval = match state.intervals.pop() {
+                Some(interval) => interval,
+                None => {
+                    structures.push((Structure::from_pairs(len, &state.pairs), state.score));
+                    continue;
+                }
+            };
+            let rest = state.bound - matrix[interval].value();
+            let (i, j) = (interval.i, interval.j - 1);
+
+            let unpaired = Position::from(i, j);
+            let bound = rest + matrix[unpaired].value();
+            if bound >= threshold
+                && self.matrix_builder.can_be_unpaired(j)
+                && matrix[unpaired].is_feasible()
+            {
+                let mut intervals = state.intervals.clone();
+                push_interval(&mut intervals, unpaired);
+                order += 1;
+                heap.push(PartialStructure {
+                    bound,
+                    order,
+                    score: state.score,
+                    pairs: state.pairs.clone(),
+                    intervals,
+                });
+            }
+
+            for k in i..j {
+                let score = match self.matrix_builder.pair_score(k, j) {
+                    Some(score) => score,
+                    None => continue,
+                };
+                let outer = Position::from(i, k);
+                let inner = Position::from(k + 1, j);
+                if !matrix[outer].is_feasible() || !matrix[inner].is_feasible() {
+                    continue;
+                }
+                let bound = rest + matrix[outer].value() + matrix[inner].value() + score;
+                if bound < threshold {
+                    continue;
+                }
+
+                let mut pairs = state.pairs.clone();
+                pairs.push((k, j));
+                let mut intervals = state.intervals.clone();
+                push_interval(&mut intervals, outer);
+                push_interval(&mut intervals, inner);
+                order += 1;
+                heap.push(PartialStructure {
+                    bound,
+                    order,
+                    score: state.score + score,
+                    pairs,
+                    intervals,
+                });
+            }
+        }
+
+        structures
+    }
+}
+
+fn push_interval(intervals: &mut Vec<Position>, interval: Position) {
+    if interval.i < interval.j {
+        intervals.push(interval);
+    }
+}
+
+impl PartialEq for PartialStructure {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for PartialStructure {}
+
+impl PartialOrd for PartialStructure {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for PartialStructure {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.bound
+            .cmp(&other.bound)
+            .then_with(|| self.order.cmp(&other.order))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use crate::{brute_force, matrix_builder::NussinovMatrixBuilder, nussinov::RNASequence};
+
+    use super::SuboptimalBuilder;
+
+    // The window has to hold exactly the enumerated structures scoring at least `optimum - delta`.
+    fn check_window(matrix_builder: &NussinovMatrixBuilder, delta: usize) {
+        let (matrix, structures) = brute_force::fill(matrix_builder);
+        let suboptimal = SuboptimalBuilder::new(matrix_builder, delta, usize::MAX).build(&matrix);
+
+        let optimum = structures.iter().map(|(_, score)| *score).max().unwrap();
+        let expected: HashSet<_> = structures
+            .into_iter()
+            .filter(|(_, score)| score + delta >= optimum)
+            .collect();
+        let found: HashSet<_> = suboptimal.iter().cloned().collect();
+
+        assert_eq!(
+            found.len(),
+            suboptimal.len(),
+            "structures are reported twice"
+        );
+        assert_eq!(found, expected);
+        assert!(suboptimal.windows(2).all(|w| w[0].1 >= w[1].1));
+    }
+
+    #[test]
+    fn suboptimal_window_matches_enumeration() {
+        for sequence in brute_force::SEQUENCES {
+            let sequence = RNASequence::new(sequence).unwrap();
+            for matrix_builder in brute_force::builders(&sequence) {
+                for delta in 0..4 {
+                    check_window(&matrix_builder, delta);
+                }
+            }
+        }
+    }
+}
@@ -1,6 +1,10 @@
-use std::collections::HashMap;
+use std::rc::Rc;
 
-use crate::matrix::{Matrix, MatrixNode, Position, TraceType};
+use crate::{
+    matrix::{Matrix, Position, Trace, TraceType},
+    matrix_builder::{ClosedLoop, MatrixBuilder, MultiLoop, UnpairedType, ZukerMatrixBuilder},
+    structure::Structure,
+};
 
 pub type TracebackPaths = Vec<TracebackPath>;
 
@@ -12,166 +16,281 @@ pub enum TracebackPathElement {
     Decomposition(Vec<TracebackPathElement>, Vec<TracebackPathElement>),
 }
 
-struct TracebackTree(TracebackNode);
+// Lazily yields the structure of every path through the trace graph of a Nussinov matrix by
+// depth-first search. Only the choice points of the current path are kept; the cells that still
+// have to be traced are shared between them.
+pub struct OptimalStructures<'m, B> {
+    matrix: &'m Matrix,
+    matrix_builder: B,
+    choices: Vec<Choice>,
+    pairs: Vec<(usize, usize)>,
+    started: bool,
+}
 
-struct TracebackNode {
-    visited: bool,
+// A cell with several traces, the trace taken by the current path and the state before taking it.
+struct Choice {
     position: Position,
-    children: Trace,
-    decomposition: HashMap<Position, Vec<TracebackPathElement>>,
+    traces: Trace,
+    option: usize,
+    pending: PendingCells,
+    pairs_len: usize,
 }
 
-type Trace = Vec<TracebackType>;
-
-enum TracebackType {
-    Complementary(TracebackNode),
-    Unpaired(TracebackNode),
-    Decomposition(TracebackNode, TracebackNode),
-}
+#[derive(Clone, Default)]
+struct PendingCells(Option<Rc<(Position, PendingCells)>>);
 
 pub trait TracebackPathsBuilder {
     fn build(&self, matrix: &Matrix) -> TracebackPaths;
 }
 
-pub struct NussinovTracebackPathsBuilder();
+impl<'m, B: MatrixBuilder> OptimalStructures<'m, B> {
+    // Expects `matrix` to be filled by `matrix_builder`.
+    pub fn new(matrix: &'m Matrix, matrix_builder: B) -> OptimalStructures<'m, B> {
+        OptimalStructures {
+            matrix,
+            matrix_builder,
+            choices: vec![],
+            pairs: vec![],
+            started: false,
+        }
+    }
+
+    // Traces the pending cells along their first traces and returns the completed structure.
+    fn descend(&mut self, mut pending: PendingCells) -> Structure {
+        while let Some((position, rest)) = pending.pop() {
+            let traces = self.matrix_builder.traces(self.matrix, position);
+            if traces.len() > 1 {
+                self.choices.push(Choice {
+                    position,
+                    traces: traces.clone(),
+                    option: 0,
+                    pending: rest.clone(),
+                    pairs_len: self.pairs.len(),
+                });
+            }
+            pending = match traces.first() {
+                Some(trace_type) => self.apply(position, trace_type, rest),
+                None => rest,
+            };
+        }
+
+        let len = self.matrix.root_position().j;
+        Structure::from_pairs(len, &self.pairs)
+    }
+
+    // Switches the deepest choice point with traces left to its next trace.
+    fn backtrack(&mut self) -> Option<PendingCells> {
+        loop {
+            let choice = self.choices.last_mut()?;
+            choice.option += 1;
+            if choice.option < choice.traces.len() {
+                let (position, trace_type) =
+                    (choice.position, choice.traces[choice.option].clone());
+                let pending = choice.pending.clone();
+                self.pairs.truncate(choice.pairs_len);
+                return Some(self.apply(position, &trace_type, pending));
+            }
+            self.choices.pop();
+        }
+    }
+
+    fn apply(
+        &mut self,
+        position: Position,
+        trace_type: &TraceType,
+        pending: PendingCells,
+    ) -> PendingCells {
+        match trace_type {
+            TraceType::Complementary(inner) => {
+                self.pairs.push((position.i, position.j - 1));
+                pending.push(*inner)
+            }
+            TraceType::Unpaired(pos) => pending.push(*pos),
+            TraceType::Decomposition(pos1, pos2) => pending.push(*pos2).push(*pos1),
+            TraceType::Closed(prefix, inner) => {
+                self.pairs.push((prefix.j, inner.j));
+                pending.push(*inner).push(*prefix)
+            }
+            TraceType::Exterior(target) if target.is_diagonal() => pending,
+            TraceType::Exterior(target) => {
+                self.pairs.push((target.i, target.j - 1));
+                pending.push(target.get_complementary())
+            }
+            TraceType::Pseudoknot(..) => {
+                unreachable!("Pseudoknots are traced by `PseudoknotMatrixBuilder::structure`")
+            }
+        }
+    }
+}
+
+impl<B: MatrixBuilder> Iterator for OptimalStructures<'_, B> {
+    type Item = Structure;
 
-impl NussinovTracebackPathsBuilder {
-    pub fn new() -> NussinovTracebackPathsBuilder {
-        NussinovTracebackPathsBuilder()
+    fn next(&mut self) -> Option<Self::Item> {
+        let pending = if self.started {
+            self.backtrack()?
+        } else {
+            self.started = true;
+            let root = self.matrix.root_position();
+            if !self.matrix[root].is_feasible() {
+                return None;
+            }
+            PendingCells::default().push(root)
+        };
+
+        Some(self.descend(pending))
     }
 }
 
-impl TracebackPathsBuilder for NussinovTracebackPathsBuilder {
-    fn build(&self, matrix: &Matrix) -> TracebackPaths {
-        let mut tree = TracebackTree::new(matrix);
+impl PendingCells {
+    fn push(&self, position: Position) -> PendingCells {
+        PendingCells(Some(Rc::new((position, self.clone()))))
+    }
 
-        tree.traverse_paths()
+    fn pop(&self) -> Option<(Position, PendingCells)> {
+        self.0.as_ref().map(|cell| (cell.0, cell.1.clone()))
     }
 }
 
-impl TracebackTree {
-    fn new(matrix: &Matrix) -> TracebackTree {
-        let matrix_root = matrix.root();
-        let root = TracebackTree::get_children(&matrix_root, matrix);
+pub struct ZukerTracebackPathsBuilder<'b, 's>(&'b ZukerMatrixBuilder<'s>);
 
-        TracebackTree(root)
+impl<'b, 's> ZukerTracebackPathsBuilder<'b, 's> {
+    pub fn new(matrix_builder: &'b ZukerMatrixBuilder<'s>) -> ZukerTracebackPathsBuilder<'b, 's> {
+        ZukerTracebackPathsBuilder(matrix_builder)
     }
 
-    fn get_children(node: &MatrixNode, matrix: &Matrix) -> TracebackNode {
-        let children = node
-            .trace
-            .iter()
-            .map(|trace_type| match trace_type {
-                TraceType::Complementary(pos) => {
-                    let child_node = &matrix[*pos];
-                    let node = TracebackTree::get_children(&child_node, matrix);
-                    TracebackType::Complementary(node)
+    fn trace_exterior(&self, matrix: &Matrix, position: Position) -> TracebackPath {
+        let mut path = vec![TracebackPathElement::Single(position)];
+        let mut position = position;
+
+        while let Some(trace_type) = self.0.traces(matrix, position).into_iter().next() {
+            match trace_type {
+                TraceType::Unpaired(pos) => {
+                    path.push(TracebackPathElement::Single(pos));
+                    position = pos;
+                }
+                TraceType::Complementary(_) => {
+                    self.trace_closed(position, &mut path);
+                    break;
                 }
                 TraceType::Decomposition(pos1, pos2) => {
-                    let child_node1 = &matrix[*pos1];
-                    let child_node2 = &matrix[*pos2];
-                    let node1 = TracebackTree::get_children(child_node1, matrix);
-                    let node2 = TracebackTree::get_children(child_node2, matrix);
-                    TracebackType::Decomposition(node1, node2)
+                    let first = self.trace_exterior(matrix, pos1);
+                    let second = self.trace_exterior(matrix, pos2);
+                    path.push(TracebackPathElement::Decomposition(first, second));
+                    break;
                 }
-                TraceType::Unpaired(pos) => {
-                    let child_node = &matrix[*pos];
-                    let node = TracebackTree::get_children(&child_node, matrix);
-                    TracebackType::Unpaired(node)
+                TraceType::Closed(..) | TraceType::Exterior(_) | TraceType::Pseudoknot(..) => {
+                    unreachable!("Zuker fills only use the textbook traces")
                 }
-            })
-            .collect();
-
-        TracebackNode {
-            visited: false,
-            position: node.position,
-            children,
-            decomposition: HashMap::new(),
+            }
         }
-    }
 
-    fn traverse_paths(&mut self) -> TracebackPaths {
-        TracebackTree::traverse_node_paths(&mut self.0)
+        path
     }
 
-    fn traverse_node_paths(node: &mut TracebackNode) -> TracebackPaths {
-        let mut paths = vec![];
-        while !node.visited {
-            if let Some(path) = TracebackTree::traverse(node) {
-                let path = path.into_iter().rev().collect();
-                paths.push(path);
+    // Expects the closing cell `position` to be the last element of `path`.
+    fn trace_closed(&self, position: Position, path: &mut TracebackPath) {
+        let loops = self.0.loops();
+        let energy = loops.closed[position.into()];
+        let inner = position.get_complementary();
+        path.push(TracebackPathElement::Single(inner));
+
+        let closed_loop = self
+            .0
+            .closed_energies(&loops, &position)
+            .into_iter()
+            .find(|(_, e)| *e == energy)
+            .map(|(closed_loop, _)| closed_loop);
+        drop(loops);
+
+        match closed_loop {
+            Some(ClosedLoop::Interior(pos)) => {
+                let mut current = inner;
+                while current.i < pos.i {
+                    current = current.get_unpaired(UnpairedType::Bottom);
+                    path.push(TracebackPathElement::Single(current));
+                }
+                while current.j > pos.j {
+                    current = current.get_unpaired(UnpairedType::Left);
+                    path.push(TracebackPathElement::Single(current));
+                }
+                self.trace_closed(pos, path);
+            }
+            Some(ClosedLoop::Multi(pos1, pos2)) => {
+                let first = self.trace_multi(pos1);
+                let second = self.trace_multi(pos2);
+                path.push(TracebackPathElement::Decomposition(first, second));
             }
+            Some(ClosedLoop::Hairpin) | None => {}
         }
-        paths
     }
 
-    fn traverse(node: &mut TracebackNode) -> Option<TracebackPath> {
-        if node.children.is_empty() {
-            return Some(vec![]);
-        }
-        let position = &node.position;
-        let optional_child = node
-            .children
-            .iter_mut()
-            .find(|trace_type| match trace_type {
-                TracebackType::Complementary(n) if !n.visited => true,
-                TracebackType::Unpaired(n) if !n.visited => true,
-                TracebackType::Decomposition(f, _) => {
-                    node.decomposition.is_empty()
-                        || (node.decomposition.contains_key(&f.position)
-                            && node.decomposition.get(&f.position).unwrap().len() > 0)
-                        || !node.decomposition.contains_key(&f.position)
-                }
-                _ => false,
-            });
-        if let None = optional_child {
-            node.visited = true;
-            return None;
-        }
+    fn trace_multi(&self, position: Position) -> TracebackPath {
+        let mut path = vec![TracebackPathElement::Single(position)];
+        let mut position = position;
 
-        let trace;
-        match &mut optional_child.unwrap() {
-            TracebackType::Complementary(n) => {
-                trace = TracebackTree::traverse(n);
-            }
-            TracebackType::Unpaired(n) => {
-                trace = TracebackTree::traverse(n);
-            }
-            TracebackType::Decomposition(f, s) => {
-                if !node.decomposition.contains_key(&f.position) {
-                    let first = TracebackTree::traverse_node_paths(f);
-                    let second = TracebackTree::traverse_node_paths(s);
-
-                    let mut decomposition = vec![];
-
-                    for i in 0..first.len() {
-                        for j in 0..second.len() {
-                            let trace = TracebackPathElement::Decomposition(
-                                first[i].clone(),
-                                second[j].clone(),
-                            );
-                            decomposition.push(trace);
-                        }
-                    }
-                    node.decomposition.insert(f.position, decomposition);
-                }
+        loop {
+            let loops = self.0.loops();
+            let energy = loops.multi[position.into()];
+            let multi_loop = self
+                .0
+                .multi_energies(&loops, &position)
+                .into_iter()
+                .find(|(_, e)| *e == energy)
+                .map(|(multi_loop, _)| multi_loop);
+            drop(loops);
 
-                trace = match node.decomposition.get_mut(&f.position).unwrap().pop() {
-                    Some(t) => Some(vec![t]),
-                    None => None,
-                };
+            match multi_loop {
+                Some(MultiLoop::Unpaired(pos)) => {
+                    path.push(TracebackPathElement::Single(pos));
+                    position = pos;
+                }
+                Some(MultiLoop::Branch) => {
+                    self.trace_closed(position, &mut path);
+                    break;
+                }
+                Some(MultiLoop::Decomposition(pos1, pos2)) => {
+                    let first = self.trace_multi(pos1);
+                    let second = self.trace_multi(pos2);
+                    path.push(TracebackPathElement::Decomposition(first, second));
+                    break;
+                }
+                None => break,
             }
         }
 
-        match trace {
-            Some(mut trace) => {
-                if trace.is_empty() {
-                    node.visited = true;
-                }
-                trace.push(TracebackPathElement::Single(*position));
-                Some(trace)
+        path
+    }
+}
+
+impl TracebackPathsBuilder for ZukerTracebackPathsBuilder<'_, '_> {
+    fn build(&self, matrix: &Matrix) -> TracebackPaths {
+        let root = matrix.root_position();
+        vec![self.trace_exterior(matrix, root)]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use crate::{brute_force, nussinov::RNASequence};
+
+    use super::OptimalStructures;
+
+    #[test]
+    fn traces_every_optimal_structure_once() {
+        for sequence in brute_force::SEQUENCES {
+            let sequence = RNASequence::new(sequence).unwrap();
+            for matrix_builder in brute_force::builders(&sequence) {
+                let (matrix, structures) = brute_force::fill(&matrix_builder);
+                let expected: HashSet<_> = brute_force::optimal(&structures).into_iter().collect();
+
+                let traced: Vec<_> = OptimalStructures::new(&matrix, matrix_builder).collect();
+                let found: HashSet<_> = traced.iter().cloned().collect();
+                assert_eq!(found.len(), traced.len(), "structures are traced twice");
+                assert_eq!(found, expected);
             }
-            None => None,
         }
     }
 }
@@ -0,0 +1,510 @@
+use std::fmt::{self, Display};
+
+use crate::{
+    matrix::Triangle,
+    pair_scoring::{PairScoring, UnitScoring},
+    structure::Structure,
+};
+
+const NUCLEOTIDES: [char; 4] = ['A', 'C', 'G', 'U'];
+
+// Pair scores are log-odds in tenths of a bit.
+const SCORE_SCALE: f64 = 10.0;
+
+// Added to every count so that events missing from the training data keep a small probability.
+const PSEUDOCOUNT: f64 = 1.0;
+
+// Rule probabilities of the unambiguous Nussinov grammar S -> ε | S a | S a S b, the grammar
+// behind `NussinovMatrixBuilder::rules`. `pair` is the probability of the bifurcation S a S b,
+// which emits a base pair; the emissions are indexed in `A`, `C`, `G`, `U` order.
+#[derive(Debug, Clone, PartialEq)]
+pub struct GrammarParameters {
+    pub end: f64,
+    pub unpaired: f64,
+    pub pair: f64,
+    pub unpaired_emissions: [f64; 4],
+    pub pair_emissions: [[f64; 4]; 4],
+}
+
+// Integer pair scores derived from grammar parameters, usable wherever a scoring table is.
+pub struct GrammarScoring([[Option<usize>; 4]; 4]);
+
+// A training sequence, with its structure if it is known.
+pub struct TrainingExample {
+    sequence: Vec<usize>,
+    structure: Option<Structure>,
+}
+
+// Learns grammar parameters by maximum likelihood: known structures are counted directly, and
+// the structures of unlabeled sequences are estimated by inside–outside expectation maximization.
+pub struct Trainer {
+    minimal_loop_length: usize,
+    iterations: usize,
+    tolerance: f64,
+}
+
+// Expected number of uses of every rule and emission.
+#[derive(Default)]
+struct Counts {
+    end: f64,
+    unpaired: f64,
+    pair: f64,
+    unpaired_emissions: [f64; 4],
+    pair_emissions: [[f64; 4]; 4],
+}
+
+fn nucleotide_index(nucleotide: char) -> Option<usize> {
+    NUCLEOTIDES.iter().position(|n| *n == nucleotide)
+}
+
+impl GrammarParameters {
+    // First line of a parameter file.
+    pub const HEADER: &'static str = "# SCFG parameters";
+
+    // Starting point of the training: canonical pairs are ten times as likely as the others,
+    // which breaks the symmetry that expectation maximization cannot break on its own.
+    pub fn initial() -> GrammarParameters {
+        let mut pair_emissions = [[0.0; 4]; 4];
+        for (x, first) in NUCLEOTIDES.iter().enumerate() {
+            for (y, second) in NUCLEOTIDES.iter().enumerate() {
+                let canonical = UnitScoring.score(*first, *second).is_some();
+                pair_emissions[x][y] = if canonical { 10.0 } else { 1.0 };
+            }
+        }
+        let total: f64 = pair_emissions.iter().flatten().sum();
+        pair_emissions
+            .iter_mut()
+            .flatten()
+            .for_each(|p| *p /= total);
+
+        GrammarParameters {
+            end: 0.1,
+            unpaired: 0.8,
+            pair: 0.1,
+            unpaired_emissions: [0.25; 4],
+            pair_emissions,
+        }
+    }
+
+    pub fn is_parameter_file(content: &str) -> bool {
+        content.lines().next().map(str::trim) == Some(GrammarParameters::HEADER)
+    }
+
+    // The format written by `Display`: after the header, one probability per line, e.g.
+    // `transition pair 0.1`, `unpaired A 0.25` or `pair G C 0.2`. Missing entries are 0.
+    pub fn parse(content: &str) -> Result<GrammarParameters, String> {
+        if !GrammarParameters::is_parameter_file(content) {
+            return Err("The parameter file has no header".into());
+        }
+
+        let mut parameters = GrammarParameters {
+            end: 0.0,
+            unpaired: 0.0,
+            pair: 0.0,
+            unpaired_emissions: [0.0; 4],
+            pair_emissions: [[0.0; 4]; 4],
+        };
+        for (number, line) in content.lines().enumerate().skip(1) {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let index = |field: &str| match field.to_uppercase().chars().collect::<Vec<char>>()[..]
+            {
+                [nucleotide] => nucleotide_index(nucleotide),
+                _ => None,
+            };
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            let (entry, probability) = match fields[..] {
+                ["transition", "end", p] => (Some(&mut parameters.end), p),
+                ["transition", "unpaired", p] => (Some(&mut parameters.unpaired), p),
+                ["transition", "pair", p] => (Some(&mut parameters.pair), p),
+                ["unpaired", x, p] => (index(x).map(|x| &mut parameters.unpaired_emissions[x]), p),
+                ["pair", x, y, p] => (
+                    index(x)
+                        .zip(index(y))
+                        .map(|(x, y)| &mut parameters.pair_emissions[x][y]),
+                    p,
+                ),
+                _ => (None, ""),
+            };
+
+            let probability = probability
+                .parse::<f64>()
+                .ok()
+                .filter(|p| (0.0..=1.0).contains(p));
+            match (entry, probability) {
+                (Some(entry), Some(probability)) => *entry = probability,
+                _ => {
+                    return Err(format!(
+                        "Invalid parameter file entry in line {}",
+                        number + 1
+                    ))
+                }
+            }
+        }
+
+        Ok(parameters)
+    }
+
+    // A pair scores the log-odds of the structure gaining it against leaving both nucleotides
+    // unpaired, so the optimal structure under the scoring is the most probable parse. Pairs that
+    // would make a structure less probable are not allowed.
+    pub fn scoring(&self) -> GrammarScoring {
+        let mut scores = [[None; 4]; 4];
+        for (x, row) in scores.iter_mut().enumerate() {
+            for (y, score) in row.iter_mut().enumerate() {
+                let odds = (self.pair * self.pair_emissions[x][y] * self.end)
+                    / (self.unpaired.powi(2)
+                        * self.unpaired_emissions[x]
+                        * self.unpaired_emissions[y]);
+                let value = (odds.log2() * SCORE_SCALE).round();
+                if value.is_finite() && value > 0.0 {
+                    *score = Some(value as usize);
+                }
+            }
+        }
+        GrammarScoring(scores)
+    }
+
+    fn unpaired_weight(&self, x: usize) -> f64 {
+        self.unpaired * self.unpaired_emissions[x]
+    }
+
+    fn pair_weight(&self, x: usize, y: usize) -> f64 {
+        self.pair * self.pair_emissions[x][y]
+    }
+}
+
+impl Display for GrammarParameters {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "{}", GrammarParameters::HEADER)?;
+        writeln!(f, "transition end {}", self.end)?;
+        writeln!(f, "transition unpaired {}", self.unpaired)?;
+        writeln!(f, "transition pair {}", self.pair)?;
+        for (x, p) in self.unpaired_emissions.iter().enumerate() {
+            writeln!(f, "unpaired {} {}", NUCLEOTIDES[x], p)?;
+        }
+        for (x, row) in self.pair_emissions.iter().enumerate() {
+            for (y, p) in row.iter().enumerate() {
+                writeln!(f, "pair {} {} {}", NUCLEOTIDES[x], NUCLEOTIDES[y], p)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl PairScoring for GrammarScoring {
+    fn score(&self, first: char, second: char) -> Option<usize> {
+        self.0[nucleotide_index(first)?][nucleotide_index(second)?]
+    }
+}
+
+impl TrainingExample {
+    pub fn new(sequence: &str, structure: Option<&str>) -> Result<TrainingExample, String> {
+        let sequence = sequence
+            .to_uppercase()
+            .chars()
+            .map(nucleotide_index)
+            .collect::<Option<Vec<usize>>>()
+            .ok_or("The sequence may only contain A, C, G and U")?;
+
+        let structure = match structure {
+            Some(structure) => {
+                let structure = Structure::from_dot_bracket(structure)
+                    .filter(|s| s.len() == sequence.len() && !s.has_crossing_pairs())
+                    .ok_or("The structure is invalid or does not match its sequence")?;
+                Some(structure)
+            }
+            None => None,
+        };
+
+        Ok(TrainingExample {
+            sequence,
+            structure,
+        })
+    }
+
+    // FASTA records, each starting with a `>` header. Lines that consist of dots and brackets
+    // are the known structure of their record; records without one are unlabeled.
+    pub fn from_fasta(content: &str) -> Result<Vec<TrainingExample>, String> {
+        let mut records: Vec<(String, String)> = vec![];
+        for line in content.lines().map(str::trim) {
+            if line.starts_with('>') || records.is_empty() {
+                records.push((String::new(), String::new()));
+            }
+            if line.is_empty() || line.starts_with('>') || line.starts_with(';') {
+                continue;
+            }
+
+            let (sequence, structure) = records.last_mut().unwrap();
+            if line.chars().all(|c| matches!(c, '.' | '(' | ')')) {
+                structure.push_str(line);
+            } else {
+                sequence.push_str(line);
+            }
+        }
+
+        records
+            .into_iter()
+            .filter(|(sequence, _)| !sequence.is_empty())
+            .enumerate()
+            .map(|(number, (sequence, structure))| {
+                let structure = (!structure.is_empty()).then_some(structure.as_str());
+                TrainingExample::new(&sequence, structure)
+                    .map_err(|e| format!("Record {}: {}", number + 1, e))
+            })
+            .collect()
+    }
+}
+
+impl Trainer {
+    pub fn new(minimal_loop_length: usize) -> Trainer {
+        Trainer {
+            minimal_loop_length,
+            iterations: 100,
+            tolerance: 1e-4,
+        }
+    }
+
+    // Maximal number of expectation maximization steps.
+    pub fn with_iterations(mut self, iterations: usize) -> Trainer {
+        self.iterations = iterations;
+        self
+    }
+
+    // Training stops once the log-likelihood improves by less than this.
+    pub fn with_tolerance(mut self, tolerance: f64) -> Trainer {
+        self.tolerance = tolerance;
+        self
+    }
+
+    // The trained parameters and the log-likelihood of the examples under them. Without
+    // unlabeled examples, a single step already yields the maximum likelihood estimate. Known
+    // structures have to respect the minimal loop length, as the estimated ones do.
+    pub fn train(&self, examples: &[TrainingExample]) -> Result<(GrammarParameters, f64), String> {
+        for (number, example) in examples.iter().enumerate() {
+            let pairs = example.structure.iter().flat_map(Structure::pairs);
+            if pairs.into_iter().any(|(k, l)| !self.can_pair(k, l)) {
+                return Err(format!(
+                    "Record {}: the structure has a hairpin shorter than the minimal loop length of {}",
+                    number + 1,
+                    self.minimal_loop_length
+                ));
+            }
+        }
+
+        let mut parameters = GrammarParameters::initial();
+        let mut previous = f64::NEG_INFINITY;
+
+        for _ in 0..self.iterations {
+            let (counts, log_likelihood) = self.expectation(&parameters, examples);
+            parameters = counts.normalize();
+            if log_likelihood - previous < self.tolerance {
+                break;
+            }
+            previous = log_likelihood;
+        }
+
+        let (_, log_likelihood) = self.expectation(&parameters, examples);
+        Ok((parameters, log_likelihood))
+    }
+
+    // Expected counts over all examples and their log-likelihood under `parameters`.
+    fn expectation(
+        &self,
+        parameters: &GrammarParameters,
+        examples: &[TrainingExample],
+    ) -> (Counts, f64) {
+        let mut counts = Counts::default();
+        let mut log_likelihood = 0.0;
+        for example in examples {
+            log_likelihood += match &example.structure {
+                Some(structure) => counts.add_structure(&example.sequence, structure, parameters),
+                None => self.inside_outside(&example.sequence, parameters, &mut counts),
+            };
+        }
+        (counts, log_likelihood)
+    }
+
+    fn can_pair(&self, k: usize, l: usize) -> bool {
+        k + self.minimal_loop_length < l
+    }
+
+    // Adds the expected counts of an unlabeled sequence and returns its log-likelihood. As in
+    // `NussinovMatrixBuilder::partition_function`, every nucleotide is multiplied by `scale` to
+    // keep long sequences from underflowing; the scale is chosen so that the open chain has
+    // the weight of its final `end` rule.
+    fn inside_outside(
+        &self,
+        sequence: &[usize],
+        parameters: &GrammarParameters,
+        counts: &mut Counts,
+    ) -> f64 {
+        let n = sequence.len();
+        let log_scale = if n > 0 {
+            -sequence
+                .iter()
+                .map(|x| parameters.unpaired_weight(*x).ln())
+                .sum::<f64>()
+                / n as f64
+        } else {
+            0.0
+        };
+        let scale = log_scale.exp();
+        let unpaired = |j: usize| parameters.unpaired_weight(sequence[j]) * scale;
+        let pair =
+            |k: usize, l: usize| parameters.pair_weight(sequence[k], sequence[l]) * scale * scale;
+
+        let mut inside = Triangle::filled_with(0.0, n + 1);
+        for i in 0..=n {
+            inside[(i, i)] = parameters.end;
+        }
+        for d in 1..=n {
+            for i in 0..=(n - d) {
+                let j = i + d;
+                let mut value = inside[(i, j - 1)] * unpaired(j - 1);
+                for k in i..(j - 1) {
+                    if self.can_pair(k, j - 1) {
+                        value += inside[(i, k)] * pair(k, j - 1) * inside[(k + 1, j - 1)];
+                    }
+                }
+                inside[(i, j)] = value;
+            }
+        }
+
+        let total = inside[(0, n)];
+        let mut outside = Triangle::filled_with(0.0, n + 1);
+        outside[(0, n)] = 1.0 / total;
+
+        for d in (1..=n).rev() {
+            for i in 0..=(n - d) {
+                let j = i + d;
+                let o = outside[(i, j)];
+                if o == 0.0 {
+                    continue;
+                }
+
+                let u = unpaired(j - 1);
+                outside[(i, j - 1)] += o * u;
+                let expected = o * inside[(i, j - 1)] * u;
+                counts.unpaired += expected;
+                counts.unpaired_emissions[sequence[j - 1]] += expected;
+
+                for k in i..(j - 1) {
+                    if !self.can_pair(k, j - 1) {
+                        continue;
+                    }
+                    let w = pair(k, j - 1);
+                    outside[(i, k)] += o * w * inside[(k + 1, j - 1)];
+                    outside[(k + 1, j - 1)] += o * w * inside[(i, k)];
+                    let expected = o * w * inside[(i, k)] * inside[(k + 1, j - 1)];
+                    counts.pair += expected;
+                    counts.pair_emissions[sequence[k]][sequence[j - 1]] += expected;
+                }
+            }
+        }
+        for i in 0..=n {
+            counts.end += outside[(i, i)] * parameters.end;
+        }
+
+        total.ln() - n as f64 * log_scale
+    }
+}
+
+impl Counts {
+    // Adds the rules of the parse of a known structure and returns its log-likelihood.
+    fn add_structure(
+        &mut self,
+        sequence: &[usize],
+        structure: &Structure,
+        parameters: &GrammarParameters,
+    ) -> f64 {
+        let mut log_likelihood = parameters.end.ln();
+        self.end += 1.0;
+        for (i, x) in sequence.iter().enumerate() {
+            match structure.partner(i) {
+                Some(j) if j > i => {
+                    let y = sequence[j];
+                    self.pair += 1.0;
+                    self.end += 1.0;
+                    self.pair_emissions[*x][y] += 1.0;
+                    log_likelihood += (parameters.pair_weight(*x, y) * parameters.end).ln();
+                }
+                Some(_) => {}
+                None => {
+                    self.unpaired += 1.0;
+                    self.unpaired_emissions[*x] += 1.0;
+                    log_likelihood += parameters.unpaired_weight(*x).ln();
+                }
+            }
+        }
+        log_likelihood
+    }
+
+    fn normalize(&self) -> GrammarParameters {
+        let transitions = self.end + self.unpaired + self.pair + 3.0 * PSEUDOCOUNT;
+        let unpaired_total: f64 = self.unpaired_emissions.iter().sum::<f64>() + 4.0 * PSEUDOCOUNT;
+        let pair_total: f64 =
+            self.pair_emissions.iter().flatten().sum::<f64>() + 16.0 * PSEUDOCOUNT;
+
+        GrammarParameters {
+            end: (self.end + PSEUDOCOUNT) / transitions,
+            unpaired: (self.unpaired + PSEUDOCOUNT) / transitions,
+            pair: (self.pair + PSEUDOCOUNT) / transitions,
+            unpaired_emissions: self
+                .unpaired_emissions
+                .map(|c| (c + PSEUDOCOUNT) / unpaired_total),
+            pair_emissions: self
+                .pair_emissions
+                .map(|row| row.map(|c| (c + PSEUDOCOUNT) / pair_total)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{GrammarParameters, Trainer, TrainingExample, PSEUDOCOUNT};
+
+    // The pseudocounts make the training a maximum a posteriori estimate, so what expectation
+    // maximization cannot decrease is the log-likelihood plus the log of the Dirichlet prior.
+    fn log_prior(parameters: &GrammarParameters) -> f64 {
+        let transitions = [parameters.end, parameters.unpaired, parameters.pair];
+        transitions
+            .iter()
+            .chain(parameters.unpaired_emissions.iter())
+            .chain(parameters.pair_emissions.iter().flatten())
+            .map(|p| PSEUDOCOUNT * p.ln())
+            .sum()
+    }
+
+    #[test]
+    fn expectation_maximization_never_decreases_the_posterior() {
+        let examples = [
+            TrainingExample::new("GGGAAAUCCAUGCAUG", None).unwrap(),
+            TrainingExample::new("GCGCUUAAGCGCAUAU", None).unwrap(),
+            TrainingExample::new("AUGGCUACGUAGCUAG", None).unwrap(),
+            TrainingExample::new("GGGAAACCC", Some("(((...)))")).unwrap(),
+        ];
+
+        let mut previous = f64::NEG_INFINITY;
+        for iterations in 1..=15 {
+            let (parameters, log_likelihood) = Trainer::new(1)
+                .with_iterations(iterations)
+                .with_tolerance(f64::NEG_INFINITY)
+                .train(&examples)
+                .unwrap();
+            let posterior = log_likelihood + log_prior(&parameters);
+            assert!(posterior >= previous - 1e-9, "{} < {}", posterior, previous);
+            previous = posterior;
+        }
+    }
+
+    #[test]
+    fn rejects_hairpins_below_the_minimal_loop_length() {
+        let examples = [TrainingExample::new("GGAAACC", Some("(((.)))")).unwrap()];
+        assert!(Trainer::new(3).train(&examples).is_err());
+        assert!(Trainer::new(1).train(&examples).is_ok());
+    }
+}